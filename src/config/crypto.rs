@@ -1,27 +1,154 @@
-use bcrypt::{hash, verify};
+use actix_web::web;
+use argon2::{ThreadMode, Variant, Version};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use std::env::var;
 
 use crate::errors::{AppError, AppErrorType};
 
 pub struct CryptoService ;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgorithm {
+    Argon2id,
+    Bcrypt,
+}
+
+/// Password hashing parameters. New hashes always use these; hashes made
+/// with anything else are upgraded on the next successful login.
+#[derive(Clone, Copy, Debug)]
+pub struct HashConfig {
+    pub algorithm: HashAlgorithm,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub bcrypt_cost: u32,
+}
+
+impl HashConfig {
+    /// Reads `password_hash_algorithm` (`argon2id` or `bcrypt`),
+    /// `argon2_memory_kib`, `argon2_iterations`, `argon2_parallelism` and
+    /// `bcrypt_cost`. The Argon2id defaults follow the OWASP recommendation.
+    pub fn from_env() -> HashConfig {
+        let number = |key: &str, default: u32| {
+            var(key)
+                .map(|value| value.parse().unwrap_or_else(|_| panic!("{} must be a number", key)))
+                .unwrap_or(default)
+        };
+
+        HashConfig {
+            algorithm: match var("password_hash_algorithm").as_deref() {
+                Ok("bcrypt") => HashAlgorithm::Bcrypt,
+                Ok("argon2id") | Err(_) => HashAlgorithm::Argon2id,
+                Ok(other) => panic!("Unsupported password_hash_algorithm {}", other),
+            },
+            argon2_memory_kib: number("argon2_memory_kib", 19456),
+            argon2_iterations: number("argon2_iterations", 2),
+            argon2_parallelism: number("argon2_parallelism", 1),
+            bcrypt_cost: number("bcrypt_cost", 12),
+        }
+    }
+
+    fn argon2(&self) -> argon2::Config<'static> {
+        argon2::Config {
+            variant: Variant::Argon2id,
+            version: Version::Version13,
+            mem_cost: self.argon2_memory_kib,
+            time_cost: self.argon2_iterations,
+            lanes: self.argon2_parallelism,
+            thread_mode: ThreadMode::Sequential,
+            secret: &[],
+            ad: &[],
+            hash_length: 32,
+        }
+    }
+
+    /// Hashes `password` in the calling thread.
+    fn hash(&self, password: &str) -> Result<String, String> {
+        match self.algorithm {
+            HashAlgorithm::Argon2id => {
+                let salt: [u8; 16] = rand::random();
+                argon2::hash_encoded(password.as_bytes(), &salt, &self.argon2())
+                    .map_err(|err| err.to_string())
+            }
+            HashAlgorithm::Bcrypt => {
+                bcrypt::hash(password, self.bcrypt_cost).map_err(|err| err.to_string())
+            }
+        }
+    }
+
+    /// A hash of a random password made with these settings. Checking a
+    /// password against it costs as much as checking a real one, which
+    /// keeps logins for unknown emails from answering faster.
+    pub fn dummy_hash(&self) -> String {
+        self.hash(CryptoService::generate_token(32).as_str())
+            .expect("hashing parameters are valid")
+    }
+
+    /// Whether `hashed_password` was made with other settings than these.
+    pub fn needs_rehash(&self, hashed_password: &str) -> bool {
+        match self.algorithm {
+            HashAlgorithm::Argon2id => {
+                let params = format!(
+                    "$argon2id$v=19$m={},t={},p={}$",
+                    self.argon2_memory_kib, self.argon2_iterations, self.argon2_parallelism
+                );
+                !hashed_password.starts_with(params.as_str())
+            }
+            HashAlgorithm::Bcrypt => {
+                let cost = format!("${:02}$", self.bcrypt_cost);
+                let current = hashed_password.starts_with("$2")
+                    && matches!(hashed_password.get(3..), Some(rest) if rest.starts_with(cost.as_str()));
+                !current
+            }
+        }
+    }
+}
+
+fn hashing_error(cause: String) -> AppError {
+    AppError {
+        cause: Some(cause),
+        message: None,
+        error_type: AppErrorType::HashingError,
+    }
+}
+
 impl CryptoService {
-    pub async fn hash_password(password: String) -> Result<String, AppError> {
-        let hashed_password = hash(password, 12).map_err(|err| AppError {
-            cause: Some(err.to_string()),
-            message: None,
-            error_type: AppErrorType::HashingError,
-        })?;
+    /// Hashes on the blocking thread pool so slow KDFs do not stall the
+    /// worker serving other requests.
+    pub async fn hash_password(config: &HashConfig, password: String) -> Result<String, AppError> {
+        let config = *config;
 
-        Ok(hashed_password)
+        web::block(move || config.hash(password.as_str()))
+            .await
+            .map_err(|err| hashing_error(err.to_string()))
     }
 
+    /// Checks `password` against an Argon2 or bcrypt hash.
     pub async fn verify_hash(hashed_password: String, password: String) -> Result<bool, AppError> {
-        let verify = verify(password, hashed_password.as_str()).map_err(|err| AppError {
-            cause: Some(err.to_string()),
-            message: None,
-            error_type: AppErrorType::HashingError,
-        })?;
-        Ok(verify)
+        web::block(move || {
+            if hashed_password.starts_with("$argon2") {
+                argon2::verify_encoded(hashed_password.as_str(), password.as_bytes())
+                    .map_err(|err| err.to_string())
+            } else {
+                bcrypt::verify(password, hashed_password.as_str()).map_err(|err| err.to_string())
+            }
+        })
+        .await
+        .map_err(|err| hashing_error(err.to_string()))
+    }
+
+    /// Random alphanumeric secret suitable for one-time tokens.
+    pub fn generate_token(len: usize) -> String {
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(len)
+            .collect()
+    }
+
+    /// Fast digest for high-entropy tokens, which do not need a slow KDF.
+    pub fn hash_token(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
     }
 }
 
@@ -5,9 +5,25 @@ use std::env::var;
 
 use crate::errors::{AppError, AppErrorType};
 
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 pub struct Emailer{
     pub email: String,
-    pub password: String
+    pub password: String,
+    pub public_url: String
 }
 
 impl Emailer{
@@ -15,20 +31,46 @@ impl Emailer{
     pub fn from_defaults() -> Emailer{
         Emailer{
             email: var("email").unwrap(),
-            password: var("password").unwrap()
+            password: var("password").unwrap(),
+            public_url: var("public_url").unwrap_or_else(|_| format!("http://{}:{}", var("host").unwrap(), var("port").unwrap()))
         }
     }
 
-    pub async fn new_service(&self, to: String, content: i32) -> Result<(), AppError> {
+    pub async fn send_verification(&self, to: String, token: &str) -> Result<(), AppError> {
+        self.send(to, "Confirm your email", format!("<p>Confirm this address for your account</p>
+                                        <a href=\"{0}/verify-email/{1}\">{0}/verify-email/{1}</a>
+                                        <p>The link expires in 24 hours</p>
+                                    ", self.public_url, token)).await
+    }
+
+    pub async fn new_service(&self, to: String, content: &str) -> Result<(), AppError> {
+        self.send(to, "Password Recovery", format!("<p>You Have Requested to reset Password</p>
+                                        <h3>{}</h3> 
+                                        <p>is your recovery code, it expires in 30 minutes</p>
+                                    ", content)).await
+    }
+
+    pub async fn send_lockout_notice(&self, to: String, until: &str) -> Result<(), AppError> {
+        self.send(to, "Sign-in temporarily locked", format!("<p>We saw several failed sign-in attempts on your account</p>
+                                        <p>Sign-in is locked until {} UTC. If this was not you, consider changing your password</p>
+                                    ", until)).await
+    }
+
+    /// `change` may quote request input such as a new email address, so it
+    /// is escaped before going into the HTML body.
+    pub async fn send_security_notice(&self, to: String, change: &str) -> Result<(), AppError> {
+        self.send(to, "Your account was changed", format!("<p>{}</p>
+                                        <p>If this was not you, reset your password and sign out your other sessions</p>
+                                    ", escape_html(change))).await
+    }
+
+    pub async fn send(&self, to: String, subject: &str, html: String) -> Result<(), AppError> {
         
         let mail = EmailBuilder::new()
                                     .to(to)
                                     .from(&*self.email)
-                                    .subject("Password Recovery")
-                                    .html(format!("<p>You Have Requested to reset Password</p>
-                                        <h3>{}</h3> 
-                                        <p>is your recovery code</p>
-                                    ", content))
+                                    .subject(subject)
+                                    .html(html)
                                     .build().unwrap();
 
 
@@ -47,3 +89,16 @@ impl Emailer{
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_html_neutralizes_markup() {
+        assert_eq!(
+            escape_html("a change to <a href=\"x\">x</a>@b.c & 'd'"),
+            "a change to &lt;a href=&quot;x&quot;&gt;x&lt;/a&gt;@b.c &amp; &#x27;d&#x27;"
+        );
+    }
+}
@@ -1,29 +1,243 @@
 use chrono::Utc;
 use jsonwebtoken::{
-    decode, encode, Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, TokenData,
+    Validation,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env::var;
 
 use crate::errors::{AppError, AppErrorType};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Claims {
     pub sub: String,
+    pub sid: String,
+    pub ver: i32,
     pub exp: usize,
+    pub iat: usize,
+    /// When the user last signed in with their credentials. Refreshing keeps
+    /// it, so it tells handlers how recently the caller proved who they are.
+    #[serde(default)]
+    pub auth_time: usize,
+    pub iss: String,
+    pub aud: String,
+}
+
+/// Email confirmation link payload. Signed with the same keys as access tokens
+/// but under its own audience, so neither can stand in for the other.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailClaims {
+    pub sub: String,
+    pub email: String,
+    pub exp: usize,
+    pub iss: String,
+    pub aud: String,
+}
+
+const EMAIL_TOKEN_TTL: usize = 24 * 3600;
+
+/// Proof that the password step of a two-factor login succeeded. It cannot be
+/// used as an access token and only lives long enough to type in a code.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChallengeClaims {
+    pub sub: String,
+    pub exp: usize,
+    pub iss: String,
+    pub aud: String,
+}
+
+const CHALLENGE_TTL: usize = 300;
+
+/// Signing key plus every key still accepted for verification, keyed by `kid`.
+///
+/// Tokens without a `kid` header are checked against the active key, so a
+/// deployment can start labelling keys without invalidating issued tokens.
+#[derive(Clone)]
+pub struct JwtKeys {
+    pub algorithm: Algorithm,
+    pub kid: Option<String>,
+    pub ttl: usize,
+    pub refresh_ttl: i64,
+    pub issuer: String,
+    pub audience: String,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    previous: HashMap<String, DecodingKey>,
+}
+
+impl JwtKeys {
+    /// Reads `jwt_algorithm`, `jwt_secret` (HMAC) or `jwt_private_key_file` /
+    /// `jwt_public_key_file` (RSA, EC, EdDSA), `jwt_kid`, `jwt_ttl`,
+    /// `refresh_token_ttl`, `jwt_issuer`, `jwt_audience` and `jwt_previous_keys`.
+    ///
+    /// `jwt_previous_keys` is a comma separated list of `kid=value` pairs where
+    /// the value is a secret for HMAC algorithms and a public key file otherwise.
+    pub fn from_env() -> JwtKeys {
+        let algorithm: Algorithm = var("jwt_algorithm")
+            .unwrap_or_else(|_| "HS256".to_string())
+            .parse()
+            .expect("jwt_algorithm is not a supported algorithm");
+
+        let (encoding, decoding) = if is_hmac(algorithm) {
+            let secret = var("jwt_secret").unwrap();
+            (
+                EncodingKey::from_secret(secret.as_bytes()),
+                DecodingKey::from_secret(secret.as_bytes()),
+            )
+        } else {
+            let private = read_key_file(&var("jwt_private_key_file").unwrap());
+            let public = read_key_file(&var("jwt_public_key_file").unwrap());
+            (
+                encoding_key(algorithm, &private),
+                decoding_key(algorithm, &public),
+            )
+        };
+
+        let previous = var("jwt_previous_keys")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let mut parts = entry.trim().splitn(2, '=');
+                let kid = parts.next().unwrap().to_string();
+                let value = parts
+                    .next()
+                    .expect("jwt_previous_keys entries must look like kid=value");
+                let key = if is_hmac(algorithm) {
+                    DecodingKey::from_secret(value.as_bytes())
+                } else {
+                    decoding_key(algorithm, &read_key_file(value))
+                };
+                (kid, key)
+            })
+            .collect();
+
+        JwtKeys {
+            algorithm,
+            kid: var("jwt_kid").ok(),
+            ttl: var("jwt_ttl")
+                .map(|ttl| ttl.parse().expect("jwt_ttl must be a number of seconds"))
+                .unwrap_or(900),
+            refresh_ttl: var("refresh_token_ttl")
+                .map(|ttl| {
+                    ttl.parse()
+                        .expect("refresh_token_ttl must be a number of seconds")
+                })
+                .unwrap_or(30 * 86400),
+            issuer: var("jwt_issuer").unwrap_or_else(|_| "blog-backend".to_string()),
+            audience: var("jwt_audience").unwrap_or_else(|_| "blog-frontend".to_string()),
+            encoding,
+            decoding,
+            previous,
+        }
+    }
+
+    fn verification_key(&self, kid: Option<&str>) -> Option<&DecodingKey> {
+        match kid {
+            None => Some(&self.decoding),
+            Some(kid) if self.kid.as_deref() == Some(kid) => Some(&self.decoding),
+            Some(kid) => self.previous.get(kid),
+        }
+    }
+
+    fn validation(&self) -> Validation {
+        self.validation_for(self.audience.as_str())
+    }
+
+    fn validation_for(&self, audience: &str) -> Validation {
+        let mut validation = Validation::new(self.algorithm);
+        validation.set_issuer(&[self.issuer.as_str()]);
+        validation.set_audience(&[audience]);
+        validation
+    }
+
+    fn email_audience(&self) -> String {
+        format!("{}/verify-email", self.audience)
+    }
+
+    fn challenge_audience(&self) -> String {
+        format!("{}/2fa", self.audience)
+    }
+
+    fn header(&self) -> Header {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.kid.clone();
+        header
+    }
+
+    fn key_for(&self, token: &str) -> Result<&DecodingKey, AppError> {
+        let header = decode_header(token).map_err(|_e| AppError {
+            cause: Some(_e.to_string()),
+            message: Some("JWT Decoding Error".to_string()),
+            error_type: AppErrorType::JWTParsingError,
+        })?;
+
+        self.verification_key(header.kid.as_deref())
+            .ok_or(AppError {
+                cause: Some("UNKNOWN_KEY_ID".to_string()),
+                message: Some("JWT Decoding Error".to_string()),
+                error_type: AppErrorType::JWTParsingError,
+            })
+    }
+}
+
+fn is_hmac(algorithm: Algorithm) -> bool {
+    matches!(
+        algorithm,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    )
+}
+
+fn read_key_file(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|_e| panic!("Unable to read JWT key file {}", path))
+}
+
+fn encoding_key(algorithm: Algorithm, pem: &[u8]) -> EncodingKey {
+    match algorithm {
+        Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(pem),
+        Algorithm::EdDSA => EncodingKey::from_ed_pem(pem),
+        _ => EncodingKey::from_rsa_pem(pem),
+    }
+    .expect("Invalid JWT private key")
+}
+
+fn decoding_key(algorithm: Algorithm, pem: &[u8]) -> DecodingKey {
+    match algorithm {
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(pem),
+        _ => DecodingKey::from_rsa_pem(pem),
+    }
+    .expect("Invalid JWT public key")
 }
 
 impl Claims {
-    fn new(sub: String, exp: usize) -> Self {
-        Claims { sub, exp }
+    fn new(keys: &JwtKeys, sub: String, sid: String, ver: i32, auth_time: usize) -> Self {
+        let now = Utc::now().timestamp() as usize;
+        Claims {
+            sub,
+            sid,
+            ver,
+            exp: now + keys.ttl,
+            iat: now,
+            auth_time,
+            iss: keys.issuer.clone(),
+            aud: keys.audience.clone(),
+        }
     }
 
-    pub async fn encode_req(sub: &str) -> Result<String, AppError> {
-        let time = Utc::now().timestamp() as usize + 86400 as usize;
-        match encode(
-            &Header::new(Algorithm::HS256),
-            &Claims::new(sub.to_owned(), time),
-            &EncodingKey::from_secret(b"hello"),
-        ) {
+    /// Issues an access token for `sub` bound to session `sid` and the user's
+    /// current token version. `auth_time` is when that session signed in.
+    pub async fn encode_req(
+        keys: &JwtKeys,
+        sub: &str,
+        sid: &str,
+        ver: i32,
+        auth_time: usize,
+    ) -> Result<String, AppError> {
+        let claims = Claims::new(keys, sub.to_owned(), sid.to_owned(), ver, auth_time);
+
+        match encode(&keys.header(), &claims, &keys.encoding) {
             Ok(val) => Ok(val),
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
@@ -33,12 +247,10 @@ impl Claims {
         }
     }
 
-    pub fn decode_req(token: &str) -> Result<TokenData<Claims>, AppError> {
-        match decode::<Claims>(
-            token,
-            &DecodingKey::from_secret(b"hello"),
-            &Validation::new(Algorithm::HS256),
-        ) {
+    pub fn decode_req(keys: &JwtKeys, token: &str) -> Result<TokenData<Claims>, AppError> {
+        let key = keys.key_for(token)?;
+
+        match decode::<Claims>(token, key, &keys.validation()) {
             Ok(val) => Ok(val),
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
@@ -48,3 +260,75 @@ impl Claims {
         }
     }
 }
+
+impl EmailClaims {
+    /// Issues a link token confirming that `sub` owns `email`.
+    pub fn encode_req(keys: &JwtKeys, sub: &str, email: &str) -> Result<String, AppError> {
+        let claims = EmailClaims {
+            sub: sub.to_owned(),
+            email: email.to_owned(),
+            exp: Utc::now().timestamp() as usize + EMAIL_TOKEN_TTL,
+            iss: keys.issuer.clone(),
+            aud: keys.email_audience(),
+        };
+
+        match encode(&keys.header(), &claims, &keys.encoding) {
+            Ok(val) => Ok(val),
+            Err(_e) => Err(AppError {
+                cause: Some(_e.to_string()),
+                message: Some("JWT Encoding Error".to_string()),
+                error_type: AppErrorType::JWTParsingError,
+            }),
+        }
+    }
+
+    pub fn decode_req(keys: &JwtKeys, token: &str) -> Result<EmailClaims, AppError> {
+        let invalid = |cause: String| AppError {
+            cause: Some(cause),
+            message: Some("Invalid or expired verification link".to_string()),
+            error_type: AppErrorType::InvalidToken,
+        };
+        let key = keys
+            .key_for(token)
+            .map_err(|_e| invalid(_e.cause.unwrap_or_default()))?;
+
+        decode::<EmailClaims>(token, key, &keys.validation_for(&keys.email_audience()))
+            .map(|data| data.claims)
+            .map_err(|_e| invalid(_e.to_string()))
+    }
+}
+
+impl ChallengeClaims {
+    pub fn encode_req(keys: &JwtKeys, sub: &str) -> Result<String, AppError> {
+        let claims = ChallengeClaims {
+            sub: sub.to_owned(),
+            exp: Utc::now().timestamp() as usize + CHALLENGE_TTL,
+            iss: keys.issuer.clone(),
+            aud: keys.challenge_audience(),
+        };
+
+        match encode(&keys.header(), &claims, &keys.encoding) {
+            Ok(val) => Ok(val),
+            Err(_e) => Err(AppError {
+                cause: Some(_e.to_string()),
+                message: Some("JWT Encoding Error".to_string()),
+                error_type: AppErrorType::JWTParsingError,
+            }),
+        }
+    }
+
+    pub fn decode_req(keys: &JwtKeys, token: &str) -> Result<ChallengeClaims, AppError> {
+        let expired = |cause: String| AppError {
+            cause: Some(cause),
+            message: Some("Login challenge is invalid or expired, log in again".to_string()),
+            error_type: AppErrorType::JWtTokenError,
+        };
+        let key = keys
+            .key_for(token)
+            .map_err(|_e| expired(_e.cause.unwrap_or_default()))?;
+
+        decode::<ChallengeClaims>(token, key, &keys.validation_for(&keys.challenge_audience()))
+            .map(|data| data.claims)
+            .map_err(|_e| expired(_e.to_string()))
+    }
+}
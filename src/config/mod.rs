@@ -1,11 +1,16 @@
 use dotenv::dotenv;
 use mongodb::{Client, Database};
 use std::env::var;
+use std::net::IpAddr;
 
 use crate::errors::{AppError, AppErrorType};
+use crate::models::user::UserDetails;
 
 pub mod crypto;
+pub mod totp;
 pub mod jwt;
+pub mod oidc;
+pub mod password_policy;
 pub mod s3_aws;
 pub mod email_client;
 
@@ -14,9 +19,86 @@ pub struct Config {
     pub port: String,
     pub mongodb_uri: String,
     pub db_name: String,
-    // pub secret_key: String,
+    pub jwt: jwt::JwtKeys,
     pub email: String,
-    pub password: String
+    pub password: String,
+    pub settings: Settings,
+    pub oidc: oidc::OidcProviders,
+    pub totp_key: totp::TotpKey,
+}
+
+/// Runtime switches for account policy.
+#[derive(Clone)]
+pub struct Settings {
+    pub require_verified_email: bool,
+    pub password_policy: password_policy::PasswordPolicy,
+    pub reauth_window: usize,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub hash_config: crypto::HashConfig,
+    /// Verified against on logins for unknown emails, see
+    /// `HashConfig::dummy_hash`.
+    pub dummy_hash: String,
+}
+
+impl Settings {
+    /// Reads `require_verified_email` (default `false`), `reauth_window`
+    /// (seconds a sign-in counts as recent, default 300), `trusted_proxies`
+    /// (comma separated addresses of the reverse proxies in front of the
+    /// server, default none), the password policy and the password hashing
+    /// parameters.
+    pub fn from_env() -> Settings {
+        let hash_config = crypto::HashConfig::from_env();
+        Settings {
+            require_verified_email: var("require_verified_email")
+                .map(|flag| flag == "true" || flag == "1")
+                .unwrap_or(false),
+            password_policy: password_policy::PasswordPolicy::from_env(),
+            reauth_window: var("reauth_window")
+                .map(|secs| secs.parse().expect("reauth_window must be a number of seconds"))
+                .unwrap_or(300),
+            trusted_proxies: var("trusted_proxies")
+                .unwrap_or_default()
+                .split(',')
+                .map(|ip| ip.trim())
+                .filter(|ip| !ip.is_empty())
+                .map(|ip| ip.parse().expect("trusted_proxies must list IP addresses"))
+                .collect(),
+            dummy_hash: hash_config.dummy_hash(),
+            hash_config,
+        }
+    }
+
+    /// Address of the client behind a connection from `peer`. Only a
+    /// trusted proxy gets to name it through `X-Forwarded-For`; the list is
+    /// read from the right, each trusted hop passing on to the one before,
+    /// so entries a client made up itself are never reached.
+    pub fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let mut client = peer?;
+        let hops = forwarded_for.unwrap_or_default().rsplit(',');
+        for hop in hops {
+            if !self.trusted_proxies.contains(&client) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(ip) => client = ip,
+                Err(_) => break,
+            }
+        }
+        Some(client)
+    }
+
+    /// Rejects authors who have not confirmed their email when the
+    /// deployment requires it.
+    pub fn check_can_post(&self, user: &UserDetails) -> Result<(), AppError> {
+        if self.require_verified_email && !user.email_verified {
+            return Err(AppError {
+                cause: Some("EMAIL_NOT_VERIFIED".to_string()),
+                message: Some("Verify your email address before posting".to_string()),
+                error_type: AppErrorType::ForbiddenError,
+            });
+        }
+        Ok(())
+    }
 }
 
 impl Config {
@@ -27,10 +109,13 @@ impl Config {
             host: var("host").unwrap(),
             port: var("port").unwrap(),
             mongodb_uri: var("mongodb_uri").unwrap(),
-            // secret_key: var("SECRET_KEY").unwrap(),
+            jwt: jwt::JwtKeys::from_env(),
             db_name: var("db_name").unwrap(),
             email:  var("email").unwrap(),
             password:  var("password").unwrap(),
+            settings: Settings::from_env(),
+            oidc: oidc::OidcProviders::from_env(),
+            totp_key: totp::TotpKey::from_env(),
         }
     }
 
@@ -50,3 +135,51 @@ impl Config {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(trusted_proxies: &[&str]) -> Settings {
+        Settings {
+            require_verified_email: false,
+            password_policy: password_policy::PasswordPolicy::from_env(),
+            reauth_window: 300,
+            trusted_proxies: trusted_proxies.iter().map(|ip| ip.parse().unwrap()).collect(),
+            hash_config: crypto::HashConfig::from_env(),
+            dummy_hash: String::new(),
+        }
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_without_trusted_proxies() {
+        let settings = settings(&[]);
+        assert_eq!(
+            settings.client_ip(ip("203.0.113.7"), Some("198.51.100.1")),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn trusted_proxies_pass_on_the_client_they_saw() {
+        let settings = settings(&["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(
+            settings.client_ip(ip("10.0.0.1"), Some("203.0.113.7, 10.0.0.2")),
+            ip("203.0.113.7")
+        );
+        // Whatever the client put in front of the real entries stays unread.
+        assert_eq!(
+            settings.client_ip(ip("10.0.0.1"), Some("198.51.100.1, 203.0.113.7")),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            settings.client_ip(ip("10.0.0.1"), Some("garbage")),
+            ip("10.0.0.1")
+        );
+        assert_eq!(settings.client_ip(ip("10.0.0.1"), None), ip("10.0.0.1"));
+    }
+}
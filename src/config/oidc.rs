@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::env::var;
+use std::iter::FromIterator;
+
+use actix_web::client::Client;
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::crypto::CryptoService,
+    errors::{AppError, AppErrorType},
+};
+
+/// An OpenID Connect issuer users can sign in with.
+#[derive(Clone, Debug)]
+pub struct OidcProvider {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+}
+
+/// Configured providers, keyed by the name used in the login URL.
+#[derive(Clone, Default)]
+pub struct OidcProviders(HashMap<String, OidcProvider>);
+
+impl OidcProviders {
+    /// Reads `oidc_providers`, a comma separated list of names, and for each
+    /// name `oidc_<name>_issuer`, `oidc_<name>_client_id`,
+    /// `oidc_<name>_client_secret` (optional for public clients) and
+    /// `oidc_<name>_redirect_uri`.
+    pub fn from_env() -> OidcProviders {
+        var("oidc_providers")
+            .unwrap_or_default()
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let setting = |key: &str| var(format!("oidc_{}_{}", name, key));
+                let required = |key: &str| {
+                    setting(key).unwrap_or_else(|_| panic!("oidc_{}_{} is not set", name, key))
+                };
+                OidcProvider {
+                    name: name.to_string(),
+                    issuer: required("issuer").trim_end_matches('/').to_string(),
+                    client_id: required("client_id"),
+                    client_secret: setting("client_secret").ok(),
+                    redirect_uri: required("redirect_uri"),
+                }
+            })
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Result<&OidcProvider, AppError> {
+        self.0.get(name).ok_or(AppError {
+            cause: Some("UNKNOWN_PROVIDER".to_string()),
+            message: Some("No such sign-in provider".to_string()),
+            error_type: AppErrorType::NotFoundError,
+        })
+    }
+}
+
+impl FromIterator<OidcProvider> for OidcProviders {
+    fn from_iter<I: IntoIterator<Item = OidcProvider>>(providers: I) -> Self {
+        OidcProviders(
+            providers
+                .into_iter()
+                .map(|provider| (provider.name.clone(), provider))
+                .collect(),
+        )
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+    #[serde(default)]
+    pub id_token_signing_alg_values_supported: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Claims we rely on from a provider's ID token.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub name: Option<String>,
+    pub preferred_username: Option<String>,
+    pub picture: Option<String>,
+}
+
+/// PKCE verifier and its S256 challenge.
+pub fn pkce_pair() -> (String, String) {
+    let verifier = CryptoService::generate_token(64);
+    let challenge = code_challenge(verifier.as_str());
+    (verifier, challenge)
+}
+
+fn code_challenge(verifier: &str) -> String {
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Whether `alg` can be checked with `jwk` at all. Keeps a token header from
+/// pairing an RSA or EC key with an HMAC algorithm.
+fn fits_key(jwk: &Jwk, alg: Algorithm) -> bool {
+    use Algorithm::*;
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => matches!(alg, RS256 | RS384 | RS512 | PS256 | PS384 | PS512),
+        AlgorithmParameters::EllipticCurve(_) => matches!(alg, ES256 | ES384),
+        AlgorithmParameters::OctetKeyPair(_) => alg == EdDSA,
+        AlgorithmParameters::OctetKey(_) => false,
+    }
+}
+
+/// Algorithms an ID token signed with `jwk` may use: the key's own `alg`,
+/// else what the provider advertises, else RS256. The token header only
+/// picks among these.
+fn allowed_algorithms(jwk: &Jwk, discovery: &Discovery) -> Vec<Algorithm> {
+    let candidates: Vec<Algorithm> = match jwk.common.algorithm {
+        Some(alg) => vec![alg],
+        None if !discovery.id_token_signing_alg_values_supported.is_empty() => discovery
+            .id_token_signing_alg_values_supported
+            .iter()
+            .filter_map(|alg| alg.parse().ok())
+            .collect(),
+        None => vec![Algorithm::RS256],
+    };
+    candidates
+        .into_iter()
+        .filter(|alg| fits_key(jwk, *alg))
+        .collect()
+}
+
+fn upstream_error(cause: String) -> AppError {
+    AppError {
+        cause: Some(cause),
+        message: Some("Sign-in provider request failed".to_string()),
+        error_type: AppErrorType::UpstreamError,
+    }
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, AppError> {
+    Client::default()
+        .get(url)
+        .send()
+        .await
+        .map_err(|_e| upstream_error(_e.to_string()))?
+        .json::<T>()
+        .limit(1 << 20)
+        .await
+        .map_err(|_e| upstream_error(_e.to_string()))
+}
+
+impl OidcProvider {
+    pub async fn discover(&self) -> Result<Discovery, AppError> {
+        let discovery: Discovery =
+            get_json(format!("{}/.well-known/openid-configuration", self.issuer).as_str()).await?;
+
+        if discovery.issuer.trim_end_matches('/') != self.issuer {
+            return Err(upstream_error("ISSUER_MISMATCH".to_string()));
+        }
+        Ok(discovery)
+    }
+
+    /// URL to send the browser to for the authorization-code flow.
+    pub fn authorization_url(
+        &self,
+        discovery: &Discovery,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+    ) -> String {
+        let query = serde_urlencoded::to_string([
+            ("response_type", "code"),
+            ("client_id", self.client_id.as_str()),
+            ("redirect_uri", self.redirect_uri.as_str()),
+            ("scope", "openid email profile"),
+            ("state", state),
+            ("nonce", nonce),
+            ("code_challenge", code_challenge),
+            ("code_challenge_method", "S256"),
+        ])
+        .unwrap();
+        let separator = if discovery.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+
+        format!("{}{}{}", discovery.authorization_endpoint, separator, query)
+    }
+
+    /// Redeems an authorization code and returns the verified ID token claims.
+    pub async fn exchange_code(
+        &self,
+        discovery: &Discovery,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, AppError> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.redirect_uri.as_str()),
+            ("client_id", self.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+
+        let mut res = Client::default()
+            .post(discovery.token_endpoint.as_str())
+            .send_form(&form)
+            .await
+            .map_err(|_e| upstream_error(_e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(upstream_error(format!("TOKEN_ENDPOINT_{}", res.status())));
+        }
+        let tokens: TokenResponse = res
+            .json()
+            .limit(1 << 20)
+            .await
+            .map_err(|_e| upstream_error(_e.to_string()))?;
+
+        let claims = self
+            .verify_id_token(discovery, tokens.id_token.as_str())
+            .await?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(upstream_error("NONCE_MISMATCH".to_string()));
+        }
+        Ok(claims)
+    }
+
+    async fn verify_id_token(
+        &self,
+        discovery: &Discovery,
+        id_token: &str,
+    ) -> Result<IdTokenClaims, AppError> {
+        let header = decode_header(id_token).map_err(|_e| upstream_error(_e.to_string()))?;
+        let jwks: JwkSet = get_json(discovery.jwks_uri.as_str()).await?;
+
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None => jwks.keys.first(),
+        }
+        .ok_or_else(|| upstream_error("UNKNOWN_KEY_ID".to_string()))?;
+        let allowed = allowed_algorithms(jwk, discovery);
+        if !allowed.contains(&header.alg) {
+            return Err(upstream_error("UNEXPECTED_ALGORITHM".to_string()));
+        }
+        let key = DecodingKey::from_jwk(jwk).map_err(|_e| upstream_error(_e.to_string()))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = allowed;
+        validation.set_issuer(&[discovery.issuer.as_str()]);
+        validation.set_audience(&[self.client_id.as_str()]);
+
+        decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|_e| upstream_error(_e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn discovery(algs: &[&str]) -> Discovery {
+        Discovery {
+            issuer: "https://idp.example".to_string(),
+            authorization_endpoint: "https://idp.example/authorize".to_string(),
+            token_endpoint: "https://idp.example/token".to_string(),
+            jwks_uri: "https://idp.example/jwks".to_string(),
+            id_token_signing_alg_values_supported: algs.iter().map(|alg| alg.to_string()).collect(),
+        }
+    }
+
+    fn rsa_jwk(alg: Option<&str>) -> Jwk {
+        let mut jwk = serde_json::json!({ "kty": "RSA", "kid": "k1", "n": "sXch", "e": "AQAB" });
+        if let Some(alg) = alg {
+            jwk["alg"] = alg.into();
+        }
+        serde_json::from_value(jwk).unwrap()
+    }
+
+    #[test]
+    fn code_challenge_matches_rfc_7636() {
+        // RFC 7636, appendix B.
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn pkce_pair_is_fresh_and_consistent() {
+        let (verifier, challenge) = pkce_pair();
+        // RFC 7636 requires 43 to 128 unreserved characters.
+        assert!((43..=128).contains(&verifier.len()));
+        assert!(verifier.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_eq!(challenge, code_challenge(verifier.as_str()));
+        assert_ne!(pkce_pair().0, verifier);
+    }
+
+    #[test]
+    fn authorization_url_carries_state_nonce_and_challenge() {
+        let provider = OidcProvider {
+            name: "idp".to_string(),
+            issuer: "https://idp.example".to_string(),
+            client_id: "blog".to_string(),
+            client_secret: None,
+            redirect_uri: "https://blog.example/auth/oidc/idp/callback".to_string(),
+        };
+        let url = provider.authorization_url(&discovery(&[]), "the state", "n0nce", "chal");
+        let (endpoint, query) = url.split_at(url.find('?').unwrap());
+        assert_eq!(endpoint, "https://idp.example/authorize");
+
+        let params: HashMap<String, String> = serde_urlencoded::from_str(&query[1..]).unwrap();
+        assert_eq!(params["state"], "the state");
+        assert_eq!(params["nonce"], "n0nce");
+        assert_eq!(params["code_challenge"], "chal");
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(params["redirect_uri"], provider.redirect_uri);
+    }
+
+    #[test]
+    fn algorithms_come_from_the_key_then_the_provider() {
+        assert_eq!(
+            allowed_algorithms(&rsa_jwk(Some("PS256")), &discovery(&["RS256"])),
+            vec![Algorithm::PS256]
+        );
+        assert_eq!(
+            allowed_algorithms(&rsa_jwk(None), &discovery(&["RS384", "HS256", "bogus"])),
+            vec![Algorithm::RS384]
+        );
+        assert_eq!(
+            allowed_algorithms(&rsa_jwk(None), &discovery(&[])),
+            vec![Algorithm::RS256]
+        );
+        // An RSA key never verifies an HMAC token, whatever the key claims.
+        assert!(allowed_algorithms(&rsa_jwk(Some("HS256")), &discovery(&[])).is_empty());
+    }
+}
@@ -0,0 +1,293 @@
+use sha1::{Digest, Sha1};
+use std::{env::var, io::ErrorKind, path::PathBuf};
+
+use crate::errors::{AppError, AppErrorType, Violation};
+
+/// Passwords so common that containing one adds next to nothing.
+const COMMON_WORDS: &[&str] = &[
+    "password", "passwort", "qwerty", "azerty", "letmein", "welcome", "admin", "login",
+    "iloveyou", "monkey", "dragon", "master", "sunshine", "princess", "shadow", "football",
+    "baseball", "superman", "batman", "trustno1", "secret", "blog",
+];
+
+/// Bits charged for a whole dictionary match, roughly its rank in a small list.
+const WORD_BITS: f64 = 5.0;
+
+/// Rules a new password has to satisfy, plus an optional offline breach lookup.
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub min_score: u8,
+    pub breached_passwords_dir: Option<PathBuf>,
+}
+
+impl PasswordPolicy {
+    /// Reads `password_min_length` (default 8), `password_min_score` (0-4,
+    /// default 3) and `breached_passwords_dir`. The directory holds Pwned
+    /// Passwords range files named by the first five hex digits of the SHA-1,
+    /// each listing `SUFFIX:COUNT` lines, so lookups stay k-anonymous. A
+    /// directory that cannot be read stops startup rather than quietly
+    /// turning the breach check off.
+    pub fn from_env() -> PasswordPolicy {
+        let breached_passwords_dir = var("breached_passwords_dir").ok().map(PathBuf::from);
+        if let Some(dir) = &breached_passwords_dir {
+            if let Err(_e) = std::fs::read_dir(dir) {
+                panic!("breached_passwords_dir {} is not readable: {}", dir.display(), _e);
+            }
+        }
+
+        PasswordPolicy {
+            min_length: var("password_min_length")
+                .map(|value| value.parse().expect("password_min_length must be a number"))
+                .unwrap_or(8),
+            min_score: var("password_min_score")
+                .map(|value| value.parse().expect("password_min_score must be 0-4"))
+                .unwrap_or(3),
+            breached_passwords_dir,
+        }
+    }
+
+    /// Collects every rule `password` breaks into one 422 response.
+    pub async fn check(&self, password: &str, username: &str, email: &str) -> Result<(), AppError> {
+        let mut violations = Vec::new();
+        let mut violation = |code: &str, message: String| {
+            violations.push(Violation {
+                field: "password".to_string(),
+                code: code.to_string(),
+                message,
+            })
+        };
+
+        if password.chars().count() < self.min_length {
+            violation(
+                "PASSWORD_TOO_SHORT",
+                format!("Use at least {} characters", self.min_length),
+            );
+        }
+
+        let local_part = email.split('@').next().unwrap_or_default();
+        if matches_identity(password, &[username, email, local_part]) {
+            violation(
+                "PASSWORD_MATCHES_ACCOUNT",
+                "Do not base the password on your username or email".to_string(),
+            );
+        }
+
+        if strength_score(password, &[username, local_part]) < self.min_score {
+            violation(
+                "PASSWORD_TOO_WEAK",
+                "Choose a less predictable password".to_string(),
+            );
+        }
+
+        if self.is_breached(password).await {
+            violation(
+                "PASSWORD_BREACHED",
+                "This password has appeared in a data breach".to_string(),
+            );
+        }
+
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(AppError {
+            cause: Some("PASSWORD_POLICY".to_string()),
+            message: Some("Password does not meet the password policy".to_string()),
+            error_type: AppErrorType::ValidationError(violations),
+        })
+    }
+
+    /// Looks `password` up in the local range files. A missing range file
+    /// counts as not breached; other read errors are logged and, so the
+    /// check never locks users out, count the same.
+    async fn is_breached(&self, password: &str) -> bool {
+        let dir = match &self.breached_passwords_dir {
+            Some(dir) => dir,
+            None => return false,
+        };
+
+        let digest = format!("{:X}", Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = digest.split_at(5);
+
+        match async_std::fs::read_to_string(dir.join(format!("{}.txt", prefix))).await {
+            Ok(range) => range.lines().any(|line| {
+                line.split(':')
+                    .next()
+                    .is_some_and(|candidate| candidate.trim().eq_ignore_ascii_case(suffix))
+            }),
+            Err(_e) if _e.kind() == ErrorKind::NotFound => false,
+            Err(_e) => {
+                log::error!("Breached password lookup failed: {}", _e);
+                false
+            }
+        }
+    }
+}
+
+fn matches_identity(password: &str, identities: &[&str]) -> bool {
+    let password = password.to_lowercase();
+    identities
+        .iter()
+        .map(|identity| identity.to_lowercase())
+        .filter(|identity| identity.chars().count() >= 3)
+        .any(|identity| password.contains(identity.as_str()))
+}
+
+/// Rough 0-4 strength score in the spirit of zxcvbn. Guesses are estimated
+/// from the character pool, with repeats, runs like `abc` or `321`, common
+/// passwords and `user_inputs` (l33t spellings included) costing almost
+/// nothing, then banded at 10^3, 10^6, 10^8 and 10^10 guesses.
+pub fn strength_score(password: &str, user_inputs: &[&str]) -> u8 {
+    let chars: Vec<char> = password.chars().collect();
+    let normalized: Vec<char> = chars.iter().map(|c| unleet(*c)).collect();
+
+    let mut covered = vec![false; chars.len()];
+    let mut bits = 0.0;
+    let words = COMMON_WORDS
+        .iter()
+        .copied()
+        .chain(user_inputs.iter().copied())
+        .filter(|word| word.chars().count() >= 4);
+    for word in words {
+        let word: Vec<char> = word.chars().map(unleet).collect();
+        let mut start = 0;
+        while start + word.len() <= normalized.len() {
+            if normalized[start..start + word.len()] == word[..] {
+                covered[start..start + word.len()].iter_mut().for_each(|c| *c = true);
+                bits += WORD_BITS;
+                start += word.len();
+            } else {
+                start += 1;
+            }
+        }
+    }
+
+    let per_char = (pool_size(&chars) as f64).log2();
+    for (i, c) in chars.iter().enumerate() {
+        if covered[i] {
+            continue;
+        }
+        let continues_pattern = i > 0 && {
+            let step = *c as i64 - chars[i - 1] as i64;
+            step.abs() <= 1
+        };
+        bits += if continues_pattern { 1.0 } else { per_char };
+    }
+
+    match bits * 2f64.log10() {
+        guesses if guesses < 3.0 => 0,
+        guesses if guesses < 6.0 => 1,
+        guesses if guesses < 8.0 => 2,
+        guesses if guesses < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+fn pool_size(chars: &[char]) -> u32 {
+    let mut pool = 0;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+        pool += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        pool += 100;
+    }
+    pool.max(1)
+}
+
+fn unleet(c: char) -> char {
+    match c.to_ascii_lowercase() {
+        '0' => 'o',
+        '1' | '!' => 'i',
+        '3' => 'e',
+        '4' | '@' => 'a',
+        '5' | '$' => 's',
+        '7' => 't',
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            min_score: 3,
+            breached_passwords_dir: None,
+        }
+    }
+
+    fn codes(result: Result<(), AppError>) -> Vec<String> {
+        match result {
+            Ok(()) => vec![],
+            Err(AppError {
+                error_type: AppErrorType::ValidationError(violations),
+                ..
+            }) => violations.into_iter().map(|v| v.code).collect(),
+            Err(_e) => panic!("unexpected error {:?}", _e),
+        }
+    }
+
+    #[test]
+    fn score_bands_follow_estimated_guesses() {
+        assert_eq!(strength_score("", &[]), 0);
+        // 4, 5 and 6 characters from the full 95 symbol pool land right
+        // below 10^8, below 10^10 and above.
+        assert_eq!(strength_score("kX9#", &[]), 2);
+        assert_eq!(strength_score("kX9#m", &[]), 3);
+        assert_eq!(strength_score("kX9#mQ", &[]), 4);
+        assert_eq!(strength_score("correct horse battery staple", &[]), 4);
+    }
+
+    #[test]
+    fn repeats_and_runs_cost_almost_nothing() {
+        assert_eq!(strength_score("aaaaaaaa", &[]), 1);
+        assert_eq!(strength_score("abcdefgh", &[]), 1);
+        assert_eq!(strength_score("87654321", &[]), 1);
+    }
+
+    #[test]
+    fn common_passwords_score_zero_even_in_l33t() {
+        assert_eq!(strength_score("password", &[]), 0);
+        assert_eq!(strength_score("P@ssw0rd", &[]), 0);
+        assert_eq!(strength_score("Tr0ub4dor&3", &[]), 4);
+    }
+
+    #[test]
+    fn user_inputs_are_penalized() {
+        assert_eq!(strength_score("zebrafinch", &[]), 4);
+        assert_eq!(strength_score("zebrafinch", &["zebrafinch"]), 0);
+        assert_eq!(strength_score("z3br4finch", &["zebrafinch"]), 0);
+    }
+
+    #[actix_rt::test]
+    async fn check_reports_every_broken_rule() {
+        let policy = policy();
+        assert_eq!(
+            codes(policy.check("kX9#mQ-vT2", "alice", "alice@example.com").await),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            codes(policy.check("password1!", "alice", "alice@example.com").await),
+            vec!["PASSWORD_TOO_WEAK"]
+        );
+        assert_eq!(
+            codes(policy.check("Alice#2024", "alice", "alice@example.com").await),
+            vec!["PASSWORD_MATCHES_ACCOUNT"]
+        );
+        assert_eq!(
+            codes(policy.check("abc", "alice", "alice@example.com").await),
+            vec!["PASSWORD_TOO_SHORT", "PASSWORD_TOO_WEAK"]
+        );
+    }
+}
@@ -3,9 +3,18 @@ use actix_web::web;
 use bytes::Bytes;
 use dotenv::dotenv;
 use futures::StreamExt;
-use s3::{bucket::Bucket, creds::Credentials, region::Region};
+use s3::{bucket::Bucket, creds::Credentials, region::Region, serde_types::Object};
 use std::io::Write;
 
+use crate::errors::{AppError, AppErrorType};
+
+/// Public address of the bucket that uploaded object paths are served from.
+pub const PUBLIC_URL: &str = "https://test-blog-static.s3.ap-south-1.amazonaws.com";
+
+/// Key prefix of uploaded avatars. Nothing else is stored under it, so the
+/// orphan sweep can treat every object there as an avatar.
+pub const AVATAR_PREFIX: &str = "avatars/";
+
 #[derive(Debug, Clone)]
 pub struct Tmpfile {
     pub name: String,
@@ -37,19 +46,40 @@ pub async fn save_file(temp_files: Vec<Tmpfile>) -> std::io::Result<Vec<String>>
     Ok(res)
 }
 
-pub async fn split_payload(payload: &mut Multipart) -> (Bytes, Vec<Tmpfile>) {
+fn malformed_upload(cause: String) -> AppError {
+    AppError {
+        cause: Some(cause),
+        message: Some("Malformed multipart upload".to_string()),
+        error_type: AppErrorType::BadRequest,
+    }
+}
+
+fn temp_file_error(cause: String) -> AppError {
+    AppError {
+        cause: Some(cause),
+        message: Some("Upload Failed".to_string()),
+        error_type: AppErrorType::FileUploadError,
+    }
+}
+
+/// Splits a multipart body into the JSON `data` field and the uploaded files,
+/// which are written to `./tmp`. Parts without a name are rejected.
+pub async fn split_payload(payload: &mut Multipart) -> Result<(Bytes, Vec<Tmpfile>), AppError> {
     let mut tmp_files = Vec::new();
     let mut data = Bytes::new();
 
     while let Some(item) = payload.next().await {
-        let mut field: Field = item.unwrap();
-        let content_type = field.content_disposition().unwrap();
-        println!("{:?}", content_type);
-        let name = content_type.get_name().unwrap();
+        let mut field: Field = item.map_err(|_e| malformed_upload(_e.to_string()))?;
+        let content_type = field
+            .content_disposition()
+            .ok_or_else(|| malformed_upload("MISSING_CONTENT_DISPOSITION".to_string()))?;
+        let name = content_type
+            .get_name()
+            .ok_or_else(|| malformed_upload("MISSING_FIELD_NAME".to_string()))?;
 
         if name == "data" {
             while let Some(chuck) = field.next().await {
-                data = chuck.unwrap()
+                data = chuck.map_err(|_e| malformed_upload(_e.to_string()))?
             }
         } else {
             match content_type.get_filename() {
@@ -58,12 +88,12 @@ pub async fn split_payload(payload: &mut Multipart) -> (Bytes, Vec<Tmpfile>) {
                     let temp_path = temp_file.tmp_path.clone();
                     let mut f = web::block(move || std::fs::File::create(&temp_path))
                         .await
-                        .unwrap();
+                        .map_err(|_e| temp_file_error(_e.to_string()))?;
                     while let Some(chunk) = field.next().await {
-                        let data = chunk.unwrap();
+                        let data = chunk.map_err(|_e| malformed_upload(_e.to_string()))?;
                         f = web::block(move || f.write_all(&data).map(|_| f))
                             .await
-                            .unwrap();
+                            .map_err(|_e| temp_file_error(_e.to_string()))?;
                     }
                     tmp_files.push(temp_file.clone());
                 }
@@ -71,7 +101,7 @@ pub async fn split_payload(payload: &mut Multipart) -> (Bytes, Vec<Tmpfile>) {
             }
         }
     }
-    (data, tmp_files)
+    Ok((data, tmp_files))
 }
 
 pub async fn get_s3_bucket() -> Bucket {
@@ -88,17 +118,59 @@ pub async fn get_s3_bucket() -> Bucket {
 }
 
 pub async fn upload_file(buck: Bucket, file: &str, filename: &str) -> std::io::Result<String> {
-    let content = async_std::fs::read(file).await.unwrap();
+    let content = async_std::fs::read(file).await?;
 
     let (_, code) = buck
         .put_object(format!("/{}", filename), &content)
         .await
-        .unwrap();
+        .map_err(|_e| std::io::Error::other(_e.to_string()))?;
 
     println!("{}", code);
     Ok(format!("/{}", filename))
 }
 
+/// Object path behind a URL under `PUBLIC_URL`, or `None` for files hosted
+/// elsewhere, such as avatars from a sign-in provider.
+pub fn object_path(url: &str) -> Option<&str> {
+    url.strip_prefix(PUBLIC_URL).filter(|path| path.starts_with('/'))
+}
+
+fn s3_error(err: s3::S3Error) -> AppError {
+    AppError {
+        cause: Some(err.to_string()),
+        message: None,
+        error_type: AppErrorType::FileUploadError,
+    }
+}
+
+/// Fetches an object, failing on any status but 200 so an S3 error page is
+/// never handed back as the file's content.
+pub async fn download_file(buck: Bucket, path: &str) -> Result<Vec<u8>, AppError> {
+    let (content, code) = buck.get_object(path).await.map_err(s3_error)?;
+    if code != 200 {
+        return Err(AppError {
+            cause: Some(format!("S3_STATUS_{}", code)),
+            message: None,
+            error_type: AppErrorType::FileUploadError,
+        });
+    }
+    Ok(content)
+}
+
+pub async fn delete_file(buck: Bucket, path: &str) -> Result<(), AppError> {
+    buck.delete_object(path).await.map_err(s3_error)?;
+    Ok(())
+}
+
+/// Objects directly under `prefix`.
+pub async fn list_files(buck: Bucket, prefix: &str) -> Result<Vec<Object>, AppError> {
+    let pages = buck
+        .list(prefix.to_string(), Some("/".to_string()))
+        .await
+        .map_err(s3_error)?;
+    Ok(pages.into_iter().flat_map(|page| page.contents).collect())
+}
+
 pub fn remove_file(path: &str) {
     std::fs::remove_file(path).unwrap();
 }
@@ -0,0 +1,202 @@
+use base32::Alphabet;
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use sha1::Sha1;
+use std::env::var;
+
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Steps either side of the current one that are still accepted, to absorb
+/// clock drift between the server and the authenticator app.
+const SKEW: i64 = 1;
+
+const BASE32: Alphabet = Alphabet::RFC4648 { padding: false };
+
+/// Marks a stored secret as encrypted. Values without it are refused rather
+/// than read as a plaintext secret.
+const SEALED_PREFIX: &str = "v1:";
+
+/// RFC 6238 time-based one-time passwords (HMAC-SHA1, 6 digits, 30 seconds),
+/// the defaults every authenticator app understands.
+pub struct Totp;
+
+impl Totp {
+    /// New random 160-bit shared secret, base32 encoded.
+    pub fn generate_secret() -> String {
+        let secret: [u8; 20] = rand::random();
+        base32::encode(BASE32, &secret)
+    }
+
+    /// `otpauth://` provisioning URI to render as a QR code.
+    pub fn uri(secret: &str, account: &str, issuer: &str) -> String {
+        format!(
+            "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+            issuer = encode_component(issuer),
+            account = encode_component(account),
+            secret = secret,
+            digits = DIGITS,
+            period = STEP_SECONDS,
+        )
+    }
+
+    /// Returns the time step `code` is valid for, if any, so callers can
+    /// refuse to accept the same step twice.
+    pub fn matching_step(secret: &str, code: &str) -> Option<i64> {
+        let key = base32::decode(BASE32, secret)?;
+        let current = Utc::now().timestamp() / STEP_SECONDS;
+
+        (current - SKEW..=current + SKEW).find(|step| code_at(&key, *step) == code)
+    }
+}
+
+/// Server-side key TOTP secrets are encrypted with (AES-256-GCM) before they
+/// are stored, so a copy of the database alone cannot produce codes.
+#[derive(Clone)]
+pub struct TotpKey([u8; 32]);
+
+impl TotpKey {
+    /// Reads `totp_encryption_key`, 32 random bytes in base64.
+    pub fn from_env() -> TotpKey {
+        let key = base64::decode(var("totp_encryption_key").expect("totp_encryption_key is not set"))
+            .expect("totp_encryption_key must be base64");
+        TotpKey::from_bytes(&key)
+    }
+
+    fn from_bytes(key: &[u8]) -> TotpKey {
+        assert_eq!(key.len(), 32, "totp_encryption_key must be 32 bytes");
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(key);
+        TotpKey(bytes)
+    }
+
+    fn cipher(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.0).expect("key has the AES-256 length"))
+    }
+
+    /// Encrypts `secret` for the account `user_id`. The id is authenticated
+    /// along with it, so a sealed secret cannot be copied to another account.
+    pub fn seal(&self, user_id: &str, secret: &str) -> String {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut sealed = secret.as_bytes().to_vec();
+        self.cipher()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(user_id.as_bytes()),
+                &mut sealed,
+            )
+            .expect("a TOTP secret is far below the AES-GCM size limit");
+
+        let mut stored = nonce.to_vec();
+        stored.extend(sealed);
+        format!(
+            "{}{}",
+            SEALED_PREFIX,
+            base64::encode_config(stored, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// The secret behind a stored value, or `None` if it is not sealed, was
+    /// altered, moved from another account or sealed with another key.
+    pub fn open(&self, user_id: &str, stored: &str) -> Option<String> {
+        let sealed = stored.strip_prefix(SEALED_PREFIX)?;
+        let bytes = base64::decode_config(sealed, base64::URL_SAFE_NO_PAD).ok()?;
+        if bytes.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let mut in_out = ciphertext.to_vec();
+        let secret = self
+            .cipher()
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(user_id.as_bytes()),
+                &mut in_out,
+            )
+            .ok()?;
+        String::from_utf8(secret.to_vec()).ok()
+    }
+}
+
+fn code_at(key: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+fn encode_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_at_matches_rfc_6238() {
+        // RFC 6238, appendix B (SHA-1); six digits are the last six of the
+        // eight the RFC lists.
+        let key = b"12345678901234567890";
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for (time, code) in vectors.iter() {
+            assert_eq!(code_at(key, time / STEP_SECONDS), *code, "T = {}", time);
+        }
+    }
+
+    #[test]
+    fn matching_step_accepts_the_current_code_only() {
+        let secret = Totp::generate_secret();
+        let key = base32::decode(BASE32, secret.as_str()).unwrap();
+        let current = Utc::now().timestamp() / STEP_SECONDS;
+
+        let step = Totp::matching_step(secret.as_str(), code_at(&key, current).as_str());
+        assert!(matches!(step, Some(step) if (step - current).abs() <= SKEW));
+        assert_eq!(
+            Totp::matching_step(secret.as_str(), code_at(&key, current - 10).as_str()),
+            None
+        );
+    }
+
+    #[test]
+    fn sealed_secrets_open_only_for_their_account() {
+        let key = TotpKey::from_bytes(&[7; 32]);
+        let secret = Totp::generate_secret();
+        let sealed = key.seal("alice", secret.as_str());
+
+        assert!(!sealed.contains(secret.as_str()));
+        assert_eq!(key.open("alice", sealed.as_str()), Some(secret.clone()));
+        assert_eq!(key.open("mallory", sealed.as_str()), None);
+        assert_eq!(TotpKey::from_bytes(&[8; 32]).open("alice", sealed.as_str()), None);
+        // A secret written in the clear is not trusted.
+        assert_eq!(key.open("alice", secret.as_str()), None);
+    }
+}
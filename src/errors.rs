@@ -1,4 +1,8 @@
-use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
+use actix_web::{
+    error::{JsonPayloadError, PathError, QueryPayloadError, ResponseError},
+    http::StatusCode,
+    HttpRequest, HttpResponse,
+};
 use serde::Serialize;
 use std::fmt;
 
@@ -9,11 +13,47 @@ pub enum AppErrorType {
     JWtTokenError,
     JWTParsingError,
     FileUploadError,
-    InavlidId,
+    InvalidId,
     HashingError,
-    ALREADYEXIST,
+    AlreadyExists,
     EmailError,
-    InavlidToken
+    InvalidToken,
+    ForbiddenError,
+    TooManyRequests,
+    BadRequest,
+    UpstreamError,
+    ValidationError(Vec<Violation>),
+}
+
+impl AppErrorType {
+    /// Code reported when the error carries no more specific one.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppErrorType::DatabaseError => "DATABASE_ERROR",
+            AppErrorType::NotFoundError => "NOT_FOUND",
+            AppErrorType::JWtTokenError => "UNAUTHORIZED",
+            AppErrorType::JWTParsingError => "TOKEN_ERROR",
+            AppErrorType::FileUploadError => "FILE_ERROR",
+            AppErrorType::InvalidId => "INVALID_ID",
+            AppErrorType::HashingError => "HASHING_ERROR",
+            AppErrorType::AlreadyExists => "ALREADY_EXISTS",
+            AppErrorType::EmailError => "EMAIL_ERROR",
+            AppErrorType::InvalidToken => "INVALID_TOKEN",
+            AppErrorType::ForbiddenError => "FORBIDDEN",
+            AppErrorType::TooManyRequests => "TOO_MANY_REQUESTS",
+            AppErrorType::BadRequest => "BAD_REQUEST",
+            AppErrorType::UpstreamError => "UPSTREAM_ERROR",
+            AppErrorType::ValidationError(_) => "VALIDATION_FAILED",
+        }
+    }
+}
+
+/// One broken rule in a rejected request body.
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+    pub field: String,
+    pub code: String,
+    pub message: String,
 }
 
 #[derive(Debug)]
@@ -24,8 +64,56 @@ pub struct AppError {
 }
 
 impl AppError {
+    /// 422 for a single invalid field.
+    pub fn invalid_field(field: &str, code: &str, message: &str) -> AppError {
+        AppError {
+            cause: Some(code.to_string()),
+            message: Some(message.to_string()),
+            error_type: AppErrorType::ValidationError(vec![Violation {
+                field: field.to_string(),
+                code: code.to_string(),
+                message: message.to_string(),
+            }]),
+        }
+    }
+
+    /// A failed MongoDB operation.
+    pub fn database_error(err: mongodb::error::Error) -> AppError {
+        AppError {
+            cause: Some(err.to_string()),
+            message: None,
+            error_type: AppErrorType::DatabaseError,
+        }
+    }
+
+    /// A stored document that does not have the shape the code expects.
+    pub fn decode_error(err: bson::de::Error) -> AppError {
+        AppError {
+            cause: Some(err.to_string()),
+            message: None,
+            error_type: AppErrorType::DatabaseError,
+        }
+    }
+
+    /// Stable machine-readable code. Causes written as `UPPER_SNAKE_CASE`
+    /// constants are codes already; anything else, such as a driver error
+    /// message, falls back to the code of the error type.
+    pub fn code(&self) -> String {
+        match &self.cause {
+            Some(cause)
+                if !cause.is_empty()
+                    && cause
+                        .chars()
+                        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_') =>
+            {
+                cause.clone()
+            }
+            _ => self.error_type.code().to_string(),
+        }
+    }
+
     fn message(&self) -> String {
-        match &*self {
+        match self {
             AppError {
                 message: Some(message),
                 cause: _,
@@ -39,7 +127,7 @@ impl AppError {
         }
     }
     fn cause(&self) -> String {
-        match &*self {
+        match self {
             AppError {
                 message: _,
                 cause: Some(message),
@@ -62,8 +150,11 @@ impl fmt::Display for AppError {
 
 #[derive(Serialize)]
 pub struct AppErrorResponse {
+    pub code: String,
     pub error: String,
     pub cause: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<Violation>,
 }
 
 impl ResponseError for AppError {
@@ -74,18 +165,70 @@ impl ResponseError for AppError {
             AppErrorType::JWtTokenError => StatusCode::UNAUTHORIZED,
             AppErrorType::FileUploadError => StatusCode::INTERNAL_SERVER_ERROR,
             AppErrorType::JWTParsingError => StatusCode::INTERNAL_SERVER_ERROR,
-            AppErrorType::InavlidId => StatusCode::NOT_FOUND,
+            AppErrorType::InvalidId => StatusCode::BAD_REQUEST,
             AppErrorType::HashingError => StatusCode::INTERNAL_SERVER_ERROR,
-            AppErrorType::ALREADYEXIST => StatusCode::CREATED,
+            AppErrorType::AlreadyExists => StatusCode::CONFLICT,
             AppErrorType::EmailError => StatusCode::INTERNAL_SERVER_ERROR,
-            AppErrorType::InavlidToken => StatusCode::CREATED
+            AppErrorType::InvalidToken => StatusCode::BAD_REQUEST,
+            AppErrorType::ForbiddenError => StatusCode::FORBIDDEN,
+            AppErrorType::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            AppErrorType::BadRequest => StatusCode::BAD_REQUEST,
+            AppErrorType::UpstreamError => StatusCode::BAD_GATEWAY,
+            AppErrorType::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
         HttpResponse::build(self.status_code()).json(AppErrorResponse {
+            code: self.code(),
             error: self.message(),
             cause: self.cause(),
+            details: match &self.error_type {
+                AppErrorType::ValidationError(violations) => violations.clone(),
+                _ => Vec::new(),
+            },
         })
     }
 }
+
+/// Names the field a serde error is about, from messages like
+/// "missing field `email` at line 1 column 2".
+fn serde_field(message: &str) -> Option<&str> {
+    let start = message.find('`')? + 1;
+    let len = message[start..].find('`')?;
+    Some(&message[start..start + len])
+}
+
+fn extractor_error(message: String, fallback: &str) -> actix_web::Error {
+    let field = serde_field(message.as_str()).unwrap_or(fallback);
+    AppError::invalid_field(field, "MALFORMED_REQUEST", message.as_str()).into()
+}
+
+/// `JsonConfig` error handler answering unreadable bodies with a 422.
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::Deserialize(err) => extractor_error(err.to_string(), "body"),
+        JsonPayloadError::ContentType => AppError {
+            cause: Some("UNSUPPORTED_CONTENT_TYPE".to_string()),
+            message: Some("Send the body as application/json".to_string()),
+            error_type: AppErrorType::BadRequest,
+        }
+        .into(),
+        err => AppError {
+            cause: Some(err.to_string()),
+            message: Some("Unreadable request body".to_string()),
+            error_type: AppErrorType::BadRequest,
+        }
+        .into(),
+    }
+}
+
+/// `QueryConfig` error handler answering bad query strings with a 422.
+pub fn query_error(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    extractor_error(err.to_string(), "query")
+}
+
+/// `PathConfig` error handler answering bad path segments with a 422.
+pub fn path_error(err: PathError, _req: &HttpRequest) -> actix_web::Error {
+    extractor_error(err.to_string(), "path")
+}
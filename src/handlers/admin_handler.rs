@@ -0,0 +1,238 @@
+use actix_web::{delete, get, patch, post, web, HttpRequest, HttpResponse};
+use mongodb::Database;
+use serde_json::json;
+
+use super::page_response;
+use crate::{
+    errors::{AppError, AppErrorType},
+    middlewares::{AuthenticatedUser, ClientInfo},
+    models::{
+        audit_log::{AuditAction, AuditEvent, AuditQuery},
+        blogs::{BlogPost, Comments},
+        maintenance::{MaintenanceQuery, OrphanReport, VoteReport},
+        pagination::PageQuery,
+        user::{SetRole, Suspension, User},
+    },
+};
+
+// Mounted under `/admin` behind `RequireRole(Role::Admin)` in `configure`.
+
+#[get("/users")]
+pub async fn get_users(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, AppError> {
+    let users = User::get_users(db.get_ref(), &page).await?;
+    Ok(page_response(&req, users))
+}
+
+#[patch("/users/{user_id}/role")]
+pub async fn patch_user_role(
+    db: web::Data<Database>,
+    user_id: web::Path<String>,
+    data: web::Json<SetRole>,
+    admin: AuthenticatedUser,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    not_self(&admin, user_id.as_str())?;
+    User::set_role(db.get_ref(), user_id.as_str(), data.role).await?;
+    AuditEvent::new(AuditAction::RoleChanged, &client)
+        .actor(admin.user_id.as_str())
+        .target(user_id.as_str())
+        .detail(format!("{:?}", data.role).as_str())
+        .record(db.get_ref())
+        .await;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200
+    })))
+}
+
+#[post("/users/{user_id}/suspend")]
+pub async fn post_suspend_user(
+    db: web::Data<Database>,
+    user_id: web::Path<String>,
+    data: web::Json<Suspension>,
+    admin: AuthenticatedUser,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    not_self(&admin, user_id.as_str())?;
+    User::restrict(db.get_ref(), user_id.as_str(), Some(data.days)).await?;
+    AuditEvent::new(AuditAction::UserSuspended, &client)
+        .actor(admin.user_id.as_str())
+        .target(user_id.as_str())
+        .detail(format!("{} days", data.days).as_str())
+        .record(db.get_ref())
+        .await;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200
+    })))
+}
+
+#[post("/users/{user_id}/ban")]
+pub async fn post_ban_user(
+    db: web::Data<Database>,
+    user_id: web::Path<String>,
+    admin: AuthenticatedUser,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    not_self(&admin, user_id.as_str())?;
+    User::restrict(db.get_ref(), user_id.as_str(), None).await?;
+    AuditEvent::new(AuditAction::UserBanned, &client)
+        .actor(admin.user_id.as_str())
+        .target(user_id.as_str())
+        .record(db.get_ref())
+        .await;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200
+    })))
+}
+
+#[post("/users/{user_id}/reinstate")]
+pub async fn post_reinstate_user(
+    db: web::Data<Database>,
+    user_id: web::Path<String>,
+    admin: AuthenticatedUser,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    User::reinstate(db.get_ref(), user_id.as_str()).await?;
+    AuditEvent::new(AuditAction::UserReinstated, &client)
+        .actor(admin.user_id.as_str())
+        .target(user_id.as_str())
+        .record(db.get_ref())
+        .await;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200
+    })))
+}
+
+#[delete("/blog/{blog_id}")]
+pub async fn delete_any_blog(
+    db: web::Data<Database>,
+    blog_id: web::Path<String>,
+    admin: AuthenticatedUser,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    BlogPost::delete_blog(db.get_ref(), blog_id.as_str()).await?;
+    AuditEvent::new(AuditAction::PostDeleted, &client)
+        .actor(admin.user_id.as_str())
+        .target(blog_id.as_str())
+        .record(db.get_ref())
+        .await;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200
+    })))
+}
+
+#[delete("/comment/{comment_id}")]
+pub async fn delete_any_comment(
+    db: web::Data<Database>,
+    comment_id: web::Path<String>,
+    admin: AuthenticatedUser,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    Comments::delete(db.get_ref(), comment_id.as_str()).await?;
+    AuditEvent::new(AuditAction::CommentDeleted, &client)
+        .actor(admin.user_id.as_str())
+        .target(comment_id.as_str())
+        .record(db.get_ref())
+        .await;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200
+    })))
+}
+
+/// Security events, newest first, filtered by action, actor, target, address
+/// and time range.
+#[get("/audit-log")]
+pub async fn get_audit_log(
+    db: web::Data<Database>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, AppError> {
+    let events = AuditEvent::search(db.get_ref(), &query).await?;
+    Ok(HttpResponse::Ok().json(events))
+}
+
+/// Recounts votes from the voter lists and comment counts from the stored
+/// comments. Reports only, unless called with `?apply=true`.
+#[post("/maintenance/votes")]
+pub async fn post_reconcile_votes(
+    db: web::Data<Database>,
+    query: web::Query<MaintenanceQuery>,
+    admin: AuthenticatedUser,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    let report = VoteReport::reconcile(db.get_ref(), query.apply).await?;
+    if query.apply {
+        let fixed = report.discrepancies.iter().filter(|d| d.fixed).count();
+        let counts_fixed = report.comment_counts.iter().filter(|d| d.fixed).count();
+        AuditEvent::new(AuditAction::VotesReconciled, &client)
+            .actor(admin.user_id.as_str())
+            .detail(
+                format!(
+                    "{} of {} votes and {} of {} comment counts fixed",
+                    fixed,
+                    report.discrepancies.len(),
+                    counts_fixed,
+                    report.comment_counts.len()
+                )
+                .as_str(),
+            )
+            .record(db.get_ref())
+            .await;
+    }
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Finds comments of deleted posts, content and votes of deleted accounts
+/// and unused avatars. Reports only, unless called with `?apply=true`.
+#[post("/maintenance/orphans")]
+pub async fn post_sweep_orphans(
+    db: web::Data<Database>,
+    query: web::Query<MaintenanceQuery>,
+    admin: AuthenticatedUser,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    let report = OrphanReport::sweep(db.get_ref(), query.apply).await?;
+    if query.apply {
+        AuditEvent::new(AuditAction::OrphansSwept, &client)
+            .actor(admin.user_id.as_str())
+            .detail(
+                format!(
+                    "{} comments, {} users, {} avatars",
+                    report.orphan_comments,
+                    report.missing_users.len(),
+                    report.stale_avatars.len()
+                )
+                .as_str(),
+            )
+            .record(db.get_ref())
+            .await;
+    }
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Keeps an admin from demoting, suspending or banning themselves and leaving
+/// the site without anyone able to undo it.
+fn not_self(admin: &AuthenticatedUser, user_id: &str) -> Result<(), AppError> {
+    if admin.user_id == user_id {
+        return Err(AppError {
+            cause: Some("SELF_MODERATION".to_string()),
+            message: Some("Admins cannot change their own account status or role".to_string()),
+            error_type: AppErrorType::ForbiddenError,
+        });
+    }
+    Ok(())
+}
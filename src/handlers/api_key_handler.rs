@@ -0,0 +1,67 @@
+use actix_web::{delete, get, post, web, HttpResponse};
+use mongodb::Database;
+use serde_json::json;
+
+use crate::{
+    errors::AppError,
+    middlewares::AuthenticatedUser,
+    models::api_key::{ApiKey, NewApiKey},
+};
+
+/// Creates an API key. The key itself is only in this response.
+#[post("/api-keys")]
+pub async fn post_api_key(
+    db: web::Data<Database>,
+    data: web::Json<NewApiKey>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let (api_key, key) = ApiKey::create(db.get_ref(), user.user_id.as_str(), &data).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "_id": api_key.id,
+        "name": api_key.name,
+        "prefix": api_key.prefix,
+        "scopes": api_key.scopes,
+        "expires_at": api_key.expires_at,
+        "key": key
+    })))
+}
+
+#[get("/api-keys")]
+pub async fn get_api_keys(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let keys = ApiKey::get_by_user(db.get_ref(), user.user_id.as_str()).await?;
+
+    let res: Vec<_> = keys
+        .iter()
+        .map(|api_key| {
+            json!({
+                "_id": api_key.id,
+                "name": api_key.name,
+                "prefix": api_key.prefix,
+                "scopes": api_key.scopes,
+                "created_at": api_key.created_at,
+                "expires_at": api_key.expires_at,
+                "last_used_at": api_key.last_used_at
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[delete("/api-keys/{key_id}")]
+pub async fn delete_api_key(
+    db: web::Data<Database>,
+    key_id: web::Path<String>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    ApiKey::revoke(db.get_ref(), user.user_id.as_str(), key_id.as_str()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200
+    })))
+}
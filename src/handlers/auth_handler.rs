@@ -1,19 +1,221 @@
 use actix_web::{post, web, HttpResponse};
+use bson::oid::ObjectId;
+use chrono::Utc;
 use mongodb::Database;
 use serde_json::json;
 
-use crate::models::user::{User, UserCreds};
-use crate::{config::jwt::Claims, errors::AppError};
+use crate::models::{
+    audit_log::{AuditAction, AuditEvent},
+    login_attempt::LoginGuard,
+    session::Session,
+    token::{RefreshRequest, RefreshToken},
+    user::{TwoFactorLogin, User, UserCreds},
+};
+use crate::{
+    config::{
+        crypto::CryptoService,
+        email_client::Emailer,
+        jwt::{ChallengeClaims, Claims, JwtKeys},
+        totp::TotpKey,
+        Settings,
+    },
+    errors::{AppError, AppErrorType},
+    middlewares::ClientInfo,
+};
 
 #[post("/auth/user")]
 pub async fn post_login(
     db: web::Data<Database>,
+    keys: web::Data<JwtKeys>,
+    settings: web::Data<Settings>,
     form_data: web::Json<UserCreds>,
+    client: ClientInfo,
 ) -> Result<HttpResponse, AppError> {
-    let user = User::get_user_by_email(db.get_ref(), form_data.email.as_str()).await?;
-    if !form_data.validate(&user).await? {
-        return Ok(HttpResponse::Unauthorized().body("Incorrect Password"));
+    let user = match User::get_user_by_email(db.get_ref(), form_data.email.as_str()).await {
+        Ok(user) => user,
+        Err(_e) => {
+            let guard = LoginGuard::new(None, client.ip.as_deref());
+            guard.check(db.get_ref()).await?;
+            // Spend what checking a real password would, so response times
+            // do not tell which emails have accounts.
+            CryptoService::verify_hash(settings.dummy_hash.clone(), form_data.password.clone())
+                .await?;
+            guard.failed(db.get_ref()).await?;
+            // No account to target, so the address tried goes in the detail.
+            AuditEvent::new(AuditAction::LoginFailed, &client)
+                .detail(format!("UNKNOWN_EMAIL {}", form_data.email.trim().to_lowercase()).as_str())
+                .record(db.get_ref())
+                .await;
+            return Err(invalid_credentials());
+        }
+    };
+    let user_id = user.id.as_ref().unwrap().to_hex();
+    let guard = LoginGuard::new(user.id.as_ref(), client.ip.as_deref());
+    guard.check(db.get_ref()).await?;
+    if !form_data.validate(db.get_ref(), &settings.hash_config, &user).await? {
+        if let Some(until) = guard.failed(db.get_ref()).await? {
+            notify_lockout(user.email.clone(), &until).await;
+        }
+        AuditEvent::new(AuditAction::LoginFailed, &client)
+            .target(user_id.as_str())
+            .detail("INCORRECT_PASSWORD")
+            .record(db.get_ref())
+            .await;
+        return Err(invalid_credentials());
     }
-    let jwt = Claims::encode_req(user.id.as_ref().unwrap().to_string().as_str()).await?;
-    Ok(HttpResponse::Ok().json(json!({"_id": user.id, "username": user.username, "email": user.email, "user_avatar": user.user_avatar ,"jwt": jwt })))
+    user.status.ensure_active(user.suspended_until.as_ref())?;
+    if user.totp_enabled {
+        let challenge = ChallengeClaims::encode_req(keys.get_ref(), user.id.as_ref().unwrap().to_hex().as_str())?;
+        return Ok(HttpResponse::Ok().json(json!({ "two_factor_required": true, "challenge": challenge })));
+    }
+    guard.succeeded(db.get_ref()).await?;
+    let (jwt, refresh_token) = start_session(
+        db.get_ref(),
+        keys.get_ref(),
+        user.id.as_ref().unwrap(),
+        user.token_version,
+        client.clone(),
+    )
+    .await?;
+    AuditEvent::new(AuditAction::LoginSucceeded, &client)
+        .actor(user_id.as_str())
+        .target(user_id.as_str())
+        .detail("PASSWORD")
+        .record(db.get_ref())
+        .await;
+    Ok(HttpResponse::Ok().json(json!({"_id": user.id, "username": user.username, "email": user.email, "user_avatar": user.user_avatar ,"jwt": jwt, "refresh_token": refresh_token })))
+}
+
+/// Second login step for accounts with two-factor enabled: exchanges the
+/// challenge from `/auth/user` plus a TOTP or recovery code for tokens.
+#[post("/auth/2fa")]
+pub async fn post_two_factor(
+    db: web::Data<Database>,
+    keys: web::Data<JwtKeys>,
+    totp_key: web::Data<TotpKey>,
+    form_data: web::Json<TwoFactorLogin>,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    let claims = ChallengeClaims::decode_req(keys.get_ref(), form_data.challenge.as_str())?;
+    let user = User::get_user_by_id(db.get_ref(), claims.sub.as_str()).await?;
+    user.status.ensure_active(user.suspended_until.as_ref())?;
+
+    let guard = LoginGuard::new(user.id.as_ref(), client.ip.as_deref());
+    guard.check(db.get_ref()).await?;
+    match User::verify_second_factor(db.get_ref(), totp_key.get_ref(), &user, form_data.code.as_str()).await {
+        Ok(()) => guard.succeeded(db.get_ref()).await?,
+        Err(_e @ AppError { error_type: AppErrorType::InvalidToken, .. }) => {
+            if let Some(until) = guard.failed(db.get_ref()).await? {
+                notify_lockout(user.email.clone(), &until).await;
+            }
+            AuditEvent::new(AuditAction::LoginFailed, &client)
+                .target(claims.sub.as_str())
+                .detail("INCORRECT_SECOND_FACTOR")
+                .record(db.get_ref())
+                .await;
+            return Err(_e);
+        }
+        Err(_e) => return Err(_e),
+    }
+    let (jwt, refresh_token) = start_session(
+        db.get_ref(),
+        keys.get_ref(),
+        user.id.as_ref().unwrap(),
+        user.token_version,
+        client.clone(),
+    )
+    .await?;
+    AuditEvent::new(AuditAction::LoginSucceeded, &client)
+        .actor(claims.sub.as_str())
+        .target(claims.sub.as_str())
+        .detail("SECOND_FACTOR")
+        .record(db.get_ref())
+        .await;
+    Ok(HttpResponse::Ok().json(json!({"_id": user.id, "username": user.username, "email": user.email, "user_avatar": user.user_avatar ,"jwt": jwt, "refresh_token": refresh_token })))
+}
+
+/// Unknown email and wrong password look the same to the caller.
+fn invalid_credentials() -> AppError {
+    AppError {
+        cause: Some("INVALID_CREDENTIALS".to_string()),
+        message: Some("Incorrect email or password".to_string()),
+        error_type: AppErrorType::JWtTokenError,
+    }
+}
+
+/// Tells the account owner sign-in has been locked. Delivery failures are
+/// only logged, the lock applies either way.
+async fn notify_lockout(email: String, until: &bson::DateTime) {
+    let until = until.0.format("%Y-%m-%d %H:%M:%S").to_string();
+    if let Err(_e) = Emailer::from_defaults()
+        .send_lockout_notice(email, until.as_str())
+        .await
+    {
+        println!("{:?}", _e);
+    }
+}
+
+/// Opens a session and returns its access and refresh tokens.
+pub async fn start_session(
+    db: &Database,
+    keys: &JwtKeys,
+    user_id: &ObjectId,
+    token_version: i32,
+    client: ClientInfo,
+) -> Result<(String, String), AppError> {
+    let session_id = Session::create(db, user_id, client.user_agent, client.ip).await?;
+    let jwt = Claims::encode_req(
+        keys,
+        user_id.to_hex().as_str(),
+        session_id.to_hex().as_str(),
+        token_version,
+        Utc::now().timestamp() as usize,
+    )
+    .await?;
+    let refresh_token = RefreshToken::issue(db, user_id, &session_id, keys.refresh_ttl).await?;
+    Ok((jwt, refresh_token))
+}
+
+#[post("/auth/refresh")]
+pub async fn post_refresh(
+    db: web::Data<Database>,
+    keys: web::Data<JwtKeys>,
+    form_data: web::Json<RefreshRequest>,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    let previous = RefreshToken::consume(db.get_ref(), form_data.refresh_token.as_str()).await?;
+    let user = User::get_user_by_id(db.get_ref(), previous.user_id.to_hex().as_str()).await?;
+    user.status.ensure_active(user.suspended_until.as_ref())?;
+
+    let session = Session::touch(db.get_ref(), &previous.family, client.ip).await?;
+    let jwt = Claims::encode_req(
+        keys.get_ref(),
+        previous.user_id.to_hex().as_str(),
+        previous.family.to_hex().as_str(),
+        user.token_version,
+        session.created_at.0.timestamp() as usize,
+    )
+    .await?;
+    let refresh_token = RefreshToken::issue(
+        db.get_ref(),
+        &previous.user_id,
+        &previous.family,
+        keys.refresh_ttl,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "jwt": jwt, "refresh_token": refresh_token })))
+}
+
+#[post("/auth/logout")]
+pub async fn post_logout(
+    db: web::Data<Database>,
+    form_data: web::Json<RefreshRequest>,
+) -> Result<HttpResponse, AppError> {
+    RefreshToken::revoke(db.get_ref(), form_data.refresh_token.as_str()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200
+    })))
 }
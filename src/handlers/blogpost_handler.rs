@@ -1,16 +1,21 @@
-use std::sync::{Arc, Mutex};
-
-use actix_web::{delete, get, patch, post, web, HttpResponse};
+use actix_web::{delete, get, patch, post, put, web, HttpRequest, HttpResponse};
 use mongodb::Database;
 use serde_json::json;
 
+use super::page_response;
 use crate::{
+    config::Settings,
     errors::AppError,
+    middlewares::{authorization::Target, AuthenticatedUser, ClientInfo},
     models::{
-        blogs::{BlogPost, Comments, IncOrDec, PostBlog, PostComment, PostReply, Replies},
+        audit_log::{AuditAction, AuditEvent},
+        blogs::{
+            BlogPost, CommentListQuery, Comments, PostBlog, PostComment, PostReply, VoteRequest,
+            VoteTarget,
+        },
+        pagination::PageQuery,
         user::User,
     },
-    AppData,
 };
 
 #[get("/blog/{id}")]
@@ -23,15 +28,24 @@ pub async fn get_post(
 }
 
 #[get("/blogs")]
-pub async fn get_posts(db: web::Data<Database>) -> Result<HttpResponse, AppError> {
-    let val = BlogPost::get_all_posts(db.get_ref()).await?;
-    Ok(HttpResponse::Ok().json(val))
+pub async fn get_posts(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, AppError> {
+    let val = BlogPost::get_all_posts(db.get_ref(), &page).await?;
+    Ok(page_response(&req, val))
 }
 
 #[get("/blog/user/{user_id}")]
-pub async fn get_blog_by_uid(db: web::Data<Database>, user_id: web::Path<String>) -> Result<HttpResponse, AppError>{
-    let blogs = BlogPost::get_posts_by_uid(db.get_ref(), user_id.as_str()).await?;
-    Ok(HttpResponse::Ok().json(blogs))
+pub async fn get_blog_by_uid(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    user_id: web::Path<String>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, AppError> {
+    let blogs = BlogPost::get_posts_page_by_uid(db.get_ref(), user_id.as_str(), &page).await?;
+    Ok(page_response(&req, blogs))
 }
 
 #[patch("/blog/{blog_id}")]
@@ -39,7 +53,11 @@ pub async fn patch_posts(
     db: web::Data<Database>,
     blog: web::Json<PostBlog>,
     blog_id: web::Path<String>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, AppError> {
+    user.authorize(db.get_ref(), Target::Post(blog_id.as_str()))
+        .await?;
+
     blog.patch_posts(db.get_ref(), blog_id.as_str()).await?;
     Ok(HttpResponse::Ok().json(json! ({
         "Status": "OK",
@@ -51,11 +69,18 @@ pub async fn patch_posts(
 pub async fn delete_blog(
     db: web::Data<Database>,
     blog_id: web::Path<String>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
+    user: AuthenticatedUser,
+    client: ClientInfo,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
+    user.authorize(db.get_ref(), Target::Post(blog_id.as_str()))
+        .await?;
 
-    BlogPost::delete_blog(db.get_ref(), blog_id.as_str(), user_id.as_str()).await?;
+    BlogPost::delete_blog(db.get_ref(), blog_id.as_str()).await?;
+    AuditEvent::new(AuditAction::PostDeleted, &client)
+        .actor(user.user_id.as_str())
+        .target(blog_id.as_str())
+        .record(db.get_ref())
+        .await;
 
     Ok(HttpResponse::Ok().json(json!({
         "Status": "OK",
@@ -65,25 +90,29 @@ pub async fn delete_blog(
 
 #[get("/user-blog")]
 pub async fn get_user_posts(
+    req: HttpRequest,
     db: web::Data<Database>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
+    page: web::Query<PageQuery>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
+    let user_id = user.user_id;
 
-    let blog = BlogPost::get_posts_by_uid(db.get_ref(), user_id.as_str()).await?;
+    let blog = BlogPost::get_posts_page_by_uid(db.get_ref(), user_id.as_str(), &page).await?;
 
-    Ok(HttpResponse::Ok().json(blog))
+    Ok(page_response(&req, blog))
 }
 
 #[post("/blog")]
 pub async fn post_posts(
     db: web::Data<Database>,
     data: web::Json<PostBlog>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
+    settings: web::Data<Settings>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
+    let user_id = user.user_id;
 
     let user = User::get_user_by_id(db.get_ref(), user_id.as_str()).await?;
+    settings.check_can_post(&user)?;
 
     let blog = BlogPost::new(
         data.title.to_owned(),
@@ -102,8 +131,19 @@ pub async fn post_posts(
 pub async fn post_comments(
     db: web::Data<Database>,
     form_data: web::Json<PostComment>,
+    settings: web::Data<Settings>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, AppError> {
-    let id = form_data.save(db.get_ref()).await?;
+    let author = User::get_user_by_id(db.get_ref(), user.user_id.as_str()).await?;
+    settings.check_can_post(&author)?;
+
+    let id = form_data
+        .save(
+            db.get_ref(),
+            user.user_id.as_str(),
+            author.username.as_str(),
+        )
+        .await?;
     Ok(HttpResponse::Ok().json(json! ({
         "Status": "Ok",
         "response": 200,
@@ -116,17 +156,34 @@ pub async fn get_comment(
     db: web::Data<Database>,
     id: web::Path<String>,
 ) -> Result<HttpResponse, AppError> {
-    println!("{}", id.to_string());
-    let res = Comments::get_comments_by_post(db.get_ref(), id.to_string().as_str()).await?;
+    let res = Comments::get_comments_by_id(db.get_ref(), id.as_str()).await?;
     Ok(HttpResponse::Ok().json(res))
 }
 
+/// Comments on a post, `?sort=newest|oldest|top`, oldest first by default.
+#[get("/blog/{id}/comments")]
+pub async fn get_post_comments(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    id: web::Path<String>,
+    list: web::Query<CommentListQuery>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, AppError> {
+    BlogPost::get_post_by_id(db.get_ref(), id.as_str()).await?;
+    let res = Comments::get_comments_by_post(db.get_ref(), id.as_str(), list.sort, &page).await?;
+    Ok(page_response(&req, res))
+}
+
 #[patch("/comment/{id}")]
 pub async fn patch_comment(
     db: web::Data<Database>,
     id: web::Path<String>,
     data: web::Json<PostComment>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, AppError> {
+    user.authorize(db.get_ref(), Target::Comment(id.as_str()))
+        .await?;
+
     data.patch_comments(db.get_ref(), id.as_str()).await?;
 
     Ok(HttpResponse::Ok().json(json! ({
@@ -139,8 +196,18 @@ pub async fn patch_comment(
 pub async fn delete_comment(
     db: web::Data<Database>,
     id: web::Path<String>,
+    user: AuthenticatedUser,
+    client: ClientInfo,
 ) -> Result<HttpResponse, AppError> {
+    user.authorize(db.get_ref(), Target::Comment(id.as_str()))
+        .await?;
+
     Comments::delete(db.get_ref(), id.as_str()).await?;
+    AuditEvent::new(AuditAction::CommentDeleted, &client)
+        .actor(user.user_id.as_str())
+        .target(id.as_str())
+        .record(db.get_ref())
+        .await;
     Ok(HttpResponse::Ok().json(json!({
         "Status": "OK",
         "response": 200,
@@ -152,9 +219,20 @@ pub async fn post_reply(
     db: web::Data<Database>,
     id: web::Path<String>,
     data: web::Json<PostReply>,
+    settings: web::Data<Settings>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, AppError> {
+    let author = User::get_user_by_id(db.get_ref(), user.user_id.as_str()).await?;
+    settings.check_can_post(&author)?;
     let comment = Comments::get_comments_by_id(db.get_ref(), id.as_str()).await?;
-    let id = comment.save_reply(db.get_ref(), data.0).await?;
+    let id = comment
+        .save_reply(
+            db.get_ref(),
+            data.0,
+            user.user_id.as_str(),
+            author.username.as_str(),
+        )
+        .await?;
     Ok(HttpResponse::Ok().json(json!({
         "Status": "OK",
         "response": 200,
@@ -167,9 +245,16 @@ pub async fn patch_reply(
     db: web::Data<Database>,
     params: web::Path<(String, String)>,
     data: web::Json<PostReply>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, AppError> {
     let (comment_id, reply_id) = params.into_inner();
 
+    user.authorize(
+        db.get_ref(),
+        Target::Reply(comment_id.as_str(), reply_id.as_str()),
+    )
+    .await?;
+
     data.patch_replies(db.get_ref(), comment_id.as_str(), reply_id.as_str())
         .await?;
 
@@ -183,289 +268,86 @@ pub async fn patch_reply(
 pub async fn delete_reply(
     db: web::Data<Database>,
     params: web::Path<(String, String)>,
+    user: AuthenticatedUser,
+    client: ClientInfo,
 ) -> Result<HttpResponse, AppError> {
     let (comment_id, reply_id) = params.into_inner();
 
-    Comments::delete_reply(db.get_ref(), comment_id.as_str(), reply_id.as_str()).await?;
-
-    Ok(HttpResponse::Ok().json(json!({
-        "Status": "OK",
-        "response": 200,
-    })))
-}
-
-#[patch("/upvote/inc/{blog_id}")]
-pub async fn upvote_handler_inc(
-    db: web::Data<Database>,
-    blog_id: web::Path<String>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
-) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
-    BlogPost::upvote(
-        db.get_ref(),
-        blog_id.as_str(),
-        user_id.as_str(),
-        IncOrDec::INC,
-    )
-    .await?;
-
-    Ok(HttpResponse::Ok().json(json!({
-        "Status": "OK",
-        "response": 200
-    })))
-}
-
-#[patch("/upvote/dec/{blog_id}")]
-pub async fn upvote_handler_dec(
-    db: web::Data<Database>,
-    blog_id: web::Path<String>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
-) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
-    BlogPost::upvote(
+    user.authorize(
         db.get_ref(),
-        blog_id.as_str(),
-        user_id.as_str(),
-        IncOrDec::DEC,
+        Target::Reply(comment_id.as_str(), reply_id.as_str()),
     )
     .await?;
 
-    Ok(HttpResponse::Ok().json(json!({
-        "Status": "OK",
-        "response": 200
-    })))
-}
-
-#[patch("/downvote/inc/{blog_id}")]
-pub async fn downvote_handler_inc(
-    db: web::Data<Database>,
-    blog_id: web::Path<String>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
-) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
-    BlogPost::downvote(
-        db.get_ref(),
-        blog_id.as_str(),
-        user_id.as_str(),
-        IncOrDec::INC,
-    )
-    .await?;
+    Comments::delete_reply(db.get_ref(), comment_id.as_str(), reply_id.as_str()).await?;
+    AuditEvent::new(AuditAction::ReplyDeleted, &client)
+        .actor(user.user_id.as_str())
+        .target(reply_id.as_str())
+        .detail(format!("comment {}", comment_id).as_str())
+        .record(db.get_ref())
+        .await;
 
     Ok(HttpResponse::Ok().json(json!({
         "Status": "OK",
-        "response": 200
+        "response": 200,
     })))
 }
 
-#[patch("/downvote/dec/{blog_id}")]
-pub async fn downvote_handler_dec(
+#[put("/blog/{blog_id}/vote")]
+pub async fn put_post_vote(
     db: web::Data<Database>,
     blog_id: web::Path<String>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
-) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
-    BlogPost::downvote(
-        db.get_ref(),
-        blog_id.as_str(),
-        user_id.as_str(),
-        IncOrDec::DEC,
-    )
-    .await?;
-
-    Ok(HttpResponse::Ok().json(json!({
-        "Status": "OK",
-        "response": 200
-    })))
-}
-
-#[patch("/like/inc/{comment_id}")]
-pub async fn like_handler_inc(
-    db: web::Data<Database>,
-    comment_id: web::Path<String>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
-) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
-    Comments::like(
-        db.get_ref(),
-        comment_id.as_str(),
-        user_id.as_str(),
-        IncOrDec::INC,
-    )
-    .await?;
-
-    Ok(HttpResponse::Ok().json(json!({
-        "Status": "OK",
-        "response": 200
-    })))
-}
-
-#[patch("/like/dec/{comment_id}")]
-pub async fn like_handler_dec(
-    db: web::Data<Database>,
-    comment_id: web::Path<String>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
-) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
-    Comments::like(
-        db.get_ref(),
-        comment_id.as_str(),
-        user_id.as_str(),
-        IncOrDec::DEC,
-    )
-    .await?;
-
-    Ok(HttpResponse::Ok().json(json!({
-        "Status": "OK",
-        "response": 200
-    })))
-}
-
-#[patch("/dislike/inc/{comment_id}")]
-pub async fn dislike_handler_inc(
-    db: web::Data<Database>,
-    comment_id: web::Path<String>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
+    vote: web::Json<VoteRequest>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
-    Comments::dislike(
-        db.get_ref(),
-        comment_id.as_str(),
-        user_id.as_str(),
-        IncOrDec::INC,
-    )
-    .await?;
+    VoteTarget::post(blog_id.as_str())
+        .await?
+        .vote(db.get_ref(), user.user_id.as_str(), vote.value)
+        .await?;
 
     Ok(HttpResponse::Ok().json(json!({
         "Status": "OK",
-        "response": 200
+        "response": 200,
+        "value": vote.value
     })))
 }
 
-#[patch("/dislike/dec/{comment_id}")]
-pub async fn dislike_handler_dec(
+#[put("/comment/{comment_id}/vote")]
+pub async fn put_comment_vote(
     db: web::Data<Database>,
     comment_id: web::Path<String>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
-) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
-    Comments::dislike(
-        db.get_ref(),
-        comment_id.as_str(),
-        user_id.as_str(),
-        IncOrDec::DEC,
-    )
-    .await?;
-
-    Ok(HttpResponse::Ok().json(json!({
-        "Status": "OK",
-        "response": 200
-    })))
-}
-
-#[patch("/reply/like/inc/{comment_id}/{reply_id}")]
-pub async fn reply_like_inc(
-    db: web::Data<Database>,
-    params: web::Path<(String, String)>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
-) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
-    let (comment_id, reply_id) = params.into_inner();
-
-    Replies::likes(
-        db.get_ref(),
-        user_id.as_str(),
-        comment_id.as_str(),
-        reply_id.as_str(),
-        IncOrDec::INC,
-    )
-    .await?;
-
-    Ok(HttpResponse::Ok().json(json!({
-        "Status": "OK",
-        "response": 200
-    })))
-}
-
-#[patch("/reply/like/dec/{comment_id}/{reply_id}")]
-pub async fn reply_like_dec(
-    db: web::Data<Database>,
-    params: web::Path<(String, String)>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
-) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
-    let (comment_id, reply_id) = params.into_inner();
-
-    Replies::likes(
-        db.get_ref(),
-        user_id.as_str(),
-        comment_id.as_str(),
-        reply_id.as_str(),
-        IncOrDec::DEC,
-    )
-    .await?;
-
-    Ok(HttpResponse::Ok().json(json!({
-        "Status": "OK",
-        "response": 200
-    })))
-}
-
-#[patch("/reply/dislike/inc/{comment_id}/{reply_id}")]
-pub async fn reply_dislike_inc(
-    db: web::Data<Database>,
-    params: web::Path<(String, String)>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
+    vote: web::Json<VoteRequest>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
-    let (comment_id, reply_id) = params.into_inner();
-
-    Replies::dislikes(
-        db.get_ref(),
-        user_id.as_str(),
-        comment_id.as_str(),
-        reply_id.as_str(),
-        IncOrDec::INC,
-    )
-    .await?;
+    VoteTarget::comment(comment_id.as_str())
+        .await?
+        .vote(db.get_ref(), user.user_id.as_str(), vote.value)
+        .await?;
 
     Ok(HttpResponse::Ok().json(json!({
         "Status": "OK",
-        "response": 200
+        "response": 200,
+        "value": vote.value
     })))
 }
 
-#[patch("/reply/dislike/dec/{comment_id}/{reply_id}")]
-pub async fn reply_dislike_dec(
+#[put("/reply-comment/{comment_id}/{reply_id}/vote")]
+pub async fn put_reply_vote(
     db: web::Data<Database>,
     params: web::Path<(String, String)>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
+    vote: web::Json<VoteRequest>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
-
     let (comment_id, reply_id) = params.into_inner();
 
-    Replies::dislikes(
-        db.get_ref(),
-        user_id.as_str(),
-        comment_id.as_str(),
-        reply_id.as_str(),
-        IncOrDec::DEC,
-    )
-    .await?;
+    VoteTarget::reply(comment_id.as_str(), reply_id.as_str())
+        .await?
+        .vote(db.get_ref(), user.user_id.as_str(), vote.value)
+        .await?;
 
     Ok(HttpResponse::Ok().json(json!({
         "Status": "OK",
-        "response": 200
+        "response": 200,
+        "value": vote.value
     })))
 }
@@ -1,45 +1,72 @@
-use actix_web::web;
+use actix_web::{http::Method, web, HttpRequest, HttpResponse};
+use serde::Serialize;
 
+use crate::{
+    middlewares::{
+        authorization::{ApiKeyScopes, RequireRole},
+        rate_limit::RateLimitGroup,
+    },
+    models::{api_key::Scope, pagination::Page, user::Role},
+};
+
+pub mod admin_handler;
+pub mod api_key_handler;
 pub mod auth_handler;
 pub mod blogpost_handler;
+pub mod oidc_handler;
+pub mod session_handler;
+pub mod two_factor_handler;
 pub mod user_handler;
 
-use self::auth_handler::post_login;
+use self::admin_handler::{
+    delete_any_blog, delete_any_comment, get_audit_log, get_users, patch_user_role, post_ban_user,
+    post_reconcile_votes, post_reinstate_user, post_suspend_user, post_sweep_orphans,
+};
+use self::api_key_handler::{delete_api_key, get_api_keys, post_api_key};
+use self::auth_handler::{post_login, post_logout, post_refresh, post_two_factor};
 use self::blogpost_handler::{
-    delete_blog, delete_comment, delete_reply, dislike_handler_dec, dislike_handler_inc,
-    downvote_handler_dec, downvote_handler_inc, get_blog_by_uid, get_comment, get_post, get_posts,
-    get_user_posts, like_handler_dec, like_handler_inc, patch_comment, patch_posts, patch_reply,
-    post_comments, post_posts, post_reply, reply_dislike_dec, reply_dislike_inc, reply_like_dec,
-    reply_like_inc, upvote_handler_dec, upvote_handler_inc,
+    delete_blog, delete_comment, delete_reply, get_blog_by_uid, get_comment, get_post,
+    get_post_comments, get_posts, get_user_posts, patch_comment, patch_posts, patch_reply,
+    post_comments, post_posts, post_reply, put_comment_vote, put_post_vote, put_reply_vote,
 };
-use self::user_handler::{get_user, patch_password, patch_user, /*get_users,*/ post_user, forget_password, check_recovery, forget_success};
+use self::oidc_handler::{get_oidc_callback, get_oidc_login};
+use self::session_handler::{delete_session, delete_sessions, get_sessions};
+use self::two_factor_handler::{delete_two_factor, post_two_factor_enable, post_two_factor_setup};
+use self::user_handler::{delete_user, get_export, get_user, patch_password, patch_user, post_user, forget_password, check_recovery, forget_success, verify_email, resend_verification};
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(post_login)
+        .service(post_two_factor)
+        .service(get_oidc_login)
+        .service(get_oidc_callback)
+        .service(post_refresh)
+        .service(post_logout)
+        .service(get_sessions)
+        .service(delete_session)
+        .service(delete_sessions)
+        .service(post_api_key)
+        .service(get_api_keys)
+        .service(delete_api_key)
+        .service(post_two_factor_setup)
+        .service(post_two_factor_enable)
+        .service(delete_two_factor)
+        .service(get_export)
         .service(get_user)
         .service(post_user)
-        //        .service(get_users)
+        .service(delete_user)
         .service(get_post)
         .service(get_posts)
         .service(post_posts)
         .service(post_comments)
         .service(get_comment)
+        .service(get_post_comments)
         .service(post_reply)
-        .service(upvote_handler_inc)
-        .service(upvote_handler_dec)
-        .service(downvote_handler_inc)
-        .service(downvote_handler_dec)
+        .service(put_post_vote)
+        .service(put_comment_vote)
+        .service(put_reply_vote)
         .service(get_user_posts)
         .service(patch_posts)
         .service(delete_blog)
-        .service(like_handler_inc)
-        .service(like_handler_dec)
-        .service(dislike_handler_inc)
-        .service(dislike_handler_dec)
-        .service(reply_like_inc)
-        .service(reply_like_dec)
-        .service(reply_dislike_inc)
-        .service(reply_dislike_dec)
         .service(delete_comment)
         .service(patch_comment)
         .service(patch_reply)
@@ -49,5 +76,88 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
         .service(forget_password)
         .service(check_recovery)
         .service(forget_success)
-        .service(get_blog_by_uid);
+        .service(verify_email)
+        .service(resend_verification)
+        .service(get_blog_by_uid)
+        .service(
+            web::scope("/admin")
+                .wrap(RequireRole(Role::Admin))
+                .service(get_users)
+                .service(get_audit_log)
+                .service(patch_user_role)
+                .service(post_suspend_user)
+                .service(post_ban_user)
+                .service(post_reinstate_user)
+                .service(delete_any_blog)
+                .service(delete_any_comment)
+                .service(post_reconcile_votes)
+                .service(post_sweep_orphans),
+        );
+}
+
+/// Rate limits applied by `RateLimit`, each overridable through
+/// `rate_limit_<name>`.
+pub fn rate_limit_groups() -> Vec<RateLimitGroup> {
+    vec![
+        RateLimitGroup::new("login", 20, 60)
+            .route(Method::POST, "/auth/user")
+            .route(Method::POST, "/auth/2fa")
+            .route(Method::DELETE, "/user/2fa")
+            .route(Method::POST, "/auth/refresh")
+            .route(Method::GET, "/auth/oidc/{provider}")
+            .route(Method::GET, "/auth/oidc/{provider}/callback"),
+        RateLimitGroup::new("email", 5, 900)
+            .route(Method::POST, "/user")
+            .route(Method::POST, "/forget-password")
+            .route(Method::POST, "/verify-email"),
+        RateLimitGroup::new("export", 3, 3600).route(Method::GET, "/user/export"),
+        RateLimitGroup::new("posting", 10, 60)
+            .route(Method::POST, "/blog")
+            .route(Method::POST, "/comment")
+            .route(Method::POST, "/reply-comment/{id}"),
+        RateLimitGroup::new("voting", 60, 60)
+            .route(Method::PUT, "/blog/{blog_id}/vote")
+            .route(Method::PUT, "/comment/{comment_id}/vote")
+            .route(Method::PUT, "/reply-comment/{comment_id}/{reply_id}/vote"),
+    ]
+}
+
+/// Routes API keys may call and the scope each needs. Anything not listed,
+/// such as account, session and key management, requires a login.
+pub fn api_key_scopes() -> ApiKeyScopes {
+    ApiKeyScopes::default()
+        .route(Method::GET, "/blogs", Scope::PostsRead)
+        .route(Method::GET, "/blog/{id}", Scope::PostsRead)
+        .route(Method::GET, "/blog/user/{user_id}", Scope::PostsRead)
+        .route(Method::GET, "/user-blog", Scope::PostsRead)
+        .route(Method::POST, "/blog", Scope::PostsWrite)
+        .route(Method::PATCH, "/blog/{blog_id}", Scope::PostsWrite)
+        .route(Method::DELETE, "/blog/{blog_id}", Scope::PostsWrite)
+        .route(Method::GET, "/comment/{id}", Scope::CommentsRead)
+        .route(Method::GET, "/blog/{id}/comments", Scope::CommentsRead)
+        .route(Method::POST, "/comment", Scope::CommentsWrite)
+        .route(Method::PATCH, "/comment/{id}", Scope::CommentsWrite)
+        .route(Method::DELETE, "/comment/{id}", Scope::CommentsWrite)
+        .route(Method::POST, "/reply-comment/{id}", Scope::CommentsWrite)
+        .route(Method::PATCH, "/reply-comment/{comment_id}/{reply_id}", Scope::CommentsWrite)
+        .route(Method::DELETE, "/reply-comment/{comment_id}/{reply_id}", Scope::CommentsWrite)
+        .route(Method::PUT, "/blog/{blog_id}/vote", Scope::VotesWrite)
+        .route(Method::PUT, "/comment/{comment_id}/vote", Scope::VotesWrite)
+        .route(Method::PUT, "/reply-comment/{comment_id}/{reply_id}/vote", Scope::VotesWrite)
+}
+
+/// Sends one page of a listing with a `Link: <...>; rel="next"` header that
+/// repeats the request's query with `after` set to the next cursor.
+pub(crate) fn page_response<T: Serialize>(req: &HttpRequest, page: Page<T>) -> HttpResponse {
+    let mut res = HttpResponse::Ok();
+    if let Some(cursor) = &page.next_cursor {
+        let mut query: Vec<(String, String)> =
+            serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+        query.retain(|(key, _)| key != "after");
+        query.push(("after".to_string(), cursor.clone()));
+        if let Ok(query) = serde_urlencoded::to_string(&query) {
+            res.header("Link", format!("<{}?{}>; rel=\"next\"", req.path(), query));
+        }
+    }
+    res.json(page)
 }
@@ -0,0 +1,507 @@
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    get,
+    http::header,
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use mongodb::Database;
+use serde_json::json;
+
+use crate::{
+    config::{
+        crypto::CryptoService,
+        jwt::{ChallengeClaims, JwtKeys},
+        oidc::{pkce_pair, OidcProviders},
+        Settings,
+    },
+    errors::{AppError, AppErrorType},
+    handlers::auth_handler::start_session,
+    middlewares::ClientInfo,
+    models::{
+        audit_log::{AuditAction, AuditEvent},
+        oidc_login::{OidcCallback, OidcLogin},
+        user::User,
+    },
+};
+
+/// Cookie binding a login in flight to the browser that started it.
+const BINDING_COOKIE: &str = "oidc_binding";
+
+fn binding_cookie(value: String) -> Cookie<'static> {
+    Cookie::build(BINDING_COOKIE, value)
+        .path("/auth/oidc")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .finish()
+}
+
+/// Starts the authorization-code flow with PKCE by redirecting to the
+/// provider's consent page.
+#[get("/auth/oidc/{provider}")]
+pub async fn get_oidc_login(
+    db: web::Data<Database>,
+    providers: web::Data<OidcProviders>,
+    provider: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let provider = providers.get(provider.as_str())?;
+    let discovery = provider.discover().await?;
+
+    let (code_verifier, code_challenge) = pkce_pair();
+    let binding = CryptoService::generate_token(32);
+    let login = OidcLogin::create(
+        db.get_ref(),
+        provider.name.as_str(),
+        code_verifier,
+        binding.as_str(),
+    )
+    .await?;
+    let url = provider.authorization_url(
+        &discovery,
+        login.state.as_str(),
+        login.nonce.as_str(),
+        code_challenge.as_str(),
+    );
+
+    Ok(HttpResponse::Found()
+        .header(header::LOCATION, url)
+        .cookie(binding_cookie(binding))
+        .finish())
+}
+
+/// Completes the flow: redeems the code, links or creates the user and logs
+/// them in exactly as `/auth/user` would.
+#[get("/auth/oidc/{provider}/callback")]
+#[allow(clippy::too_many_arguments)]
+pub async fn get_oidc_callback(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    keys: web::Data<JwtKeys>,
+    settings: web::Data<Settings>,
+    providers: web::Data<OidcProviders>,
+    provider: web::Path<String>,
+    query: web::Query<OidcCallback>,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    let provider = providers.get(provider.as_str())?;
+    let binding = req.cookie(BINDING_COOKIE);
+    let login = OidcLogin::consume(
+        db.get_ref(),
+        provider.name.as_str(),
+        query.state.as_str(),
+        binding.as_ref().map(|cookie| cookie.value()),
+    )
+    .await?;
+    let spent = binding_cookie(String::new());
+    let code = match (&query.code, &query.error) {
+        (Some(code), None) => code,
+        _ => {
+            return Err(AppError {
+                cause: query.error.clone(),
+                message: Some("Sign-in was cancelled or refused by the provider".to_string()),
+                error_type: AppErrorType::BadRequest,
+            })
+        }
+    };
+
+    let discovery = provider.discover().await?;
+    let claims = provider
+        .exchange_code(
+            &discovery,
+            code.as_str(),
+            login.code_verifier.as_str(),
+            login.nonce.as_str(),
+        )
+        .await?;
+    let user = User::from_identity(
+        db.get_ref(),
+        &settings.hash_config,
+        provider.name.as_str(),
+        &claims,
+    )
+    .await?;
+    user.status.ensure_active(user.suspended_until.as_ref())?;
+
+    let user_id = user.id.as_ref().unwrap();
+    if user.totp_enabled {
+        let challenge = ChallengeClaims::encode_req(keys.get_ref(), user_id.to_hex().as_str())?;
+        return Ok(HttpResponse::Ok()
+            .del_cookie(&spent)
+            .json(json!({ "two_factor_required": true, "challenge": challenge })));
+    }
+    let (jwt, refresh_token) = start_session(
+        db.get_ref(),
+        keys.get_ref(),
+        user_id,
+        user.token_version,
+        client.clone(),
+    )
+    .await?;
+    AuditEvent::new(AuditAction::LoginSucceeded, &client)
+        .actor(user_id.to_hex().as_str())
+        .target(user_id.to_hex().as_str())
+        .detail(format!("OIDC:{}", provider.name).as_str())
+        .record(db.get_ref())
+        .await;
+    Ok(HttpResponse::Ok().del_cookie(&spent).json(json!({"_id": user.id, "username": user.username, "email": user.email, "user_avatar": user.user_avatar ,"jwt": jwt, "refresh_token": refresh_token })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+    use bson::doc;
+    use chrono::Utc;
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use mongodb::{Client, Database};
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::config::{crypto, oidc::OidcProvider};
+
+    const SIGNING_KEY: &str = include_str!("../../tests/fixtures/oidc_signing_key.pem");
+    const SIGNING_KEY_N: &str = "ouksnD8Y-cHRmRBP_rQy52KKyd319gq2j_Q8Rj5krjEFqh2J8rMsw8uPXZ5CLtDJUIKdAqeb8EphzplFUbInCkJjNBy66MkEu22CEfK2KQ9ajTedxdoQOtBeyjelB_ia9SVmR9UpiX1htf0SJ2XuGhyPn8VyA_7-B2zYiuI3z-6RwPOCH_CkWAeMa6_oYtI9PpGPClmYsQjPWiwqgduY6OrOoAA83VCSa3C16D2Tv9ZSuPRpd2AEr4QFudlihvrjfuvYV9v2Nf5FrmryI3-Sn_PjGSZV4F3EIYBGbym_e0e_ZBZabZkwJdnv5KQMBZQDKo1Ma-Uwazpq-ypzsw5MZQ";
+    const CLIENT_ID: &str = "blog";
+    const REDIRECT_URI: &str = "http://localhost/auth/oidc/stub/callback";
+
+    /// What the stub provider knows: its own URL, the identity it vouches
+    /// for and the authorization codes it handed out.
+    #[derive(Default)]
+    struct Idp {
+        issuer: String,
+        subject: String,
+        email: String,
+        email_verified: bool,
+        wrong_nonce: bool,
+        /// code -> (PKCE challenge, nonce)
+        codes: HashMap<String, (String, String)>,
+    }
+
+    type SharedIdp = web::Data<Mutex<Idp>>;
+
+    async fn discovery(idp: SharedIdp) -> HttpResponse {
+        let issuer = idp.lock().unwrap().issuer.clone();
+        HttpResponse::Ok().json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+            "id_token_signing_alg_values_supported": ["RS256"]
+        }))
+    }
+
+    async fn jwks() -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "keys": [{ "kty": "RSA", "kid": "stub", "alg": "RS256", "use": "sig", "n": SIGNING_KEY_N, "e": "AQAB" }]
+        }))
+    }
+
+    async fn token(idp: SharedIdp, form: web::Form<HashMap<String, String>>) -> HttpResponse {
+        let mut idp = idp.lock().unwrap();
+        let (challenge, nonce) = match form.get("code").and_then(|code| idp.codes.remove(code)) {
+            Some(grant) => grant,
+            None => return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" })),
+        };
+        let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+        let expected =
+            base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+        if expected != challenge || form.get("client_id").map(String::as_str) != Some(CLIENT_ID) {
+            return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+        }
+
+        let nonce = if idp.wrong_nonce { "forged".to_string() } else { nonce };
+        let now = Utc::now().timestamp();
+        let claims = json!({
+            "iss": idp.issuer,
+            "aud": CLIENT_ID,
+            "sub": idp.subject,
+            "iat": now,
+            "exp": now + 300,
+            "nonce": nonce,
+            "email": idp.email,
+            "email_verified": idp.email_verified,
+            "preferred_username": "stubby"
+        });
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("stub".to_string());
+        let key = EncodingKey::from_rsa_pem(SIGNING_KEY.as_bytes()).unwrap();
+        let id_token = encode(&header, &claims, &key).unwrap();
+        HttpResponse::Ok().json(json!({ "access_token": "stub", "token_type": "Bearer", "id_token": id_token }))
+    }
+
+    /// A throwaway database, or None to skip when no test server is set up.
+    async fn test_db() -> Option<Database> {
+        let uri = std::env::var("MONGO_TEST_URI").ok()?;
+        let client = Client::with_uri_str(uri.as_str()).await.unwrap();
+        Some(client.database(format!("oidc_test_{}", Utc::now().timestamp_nanos()).as_str()))
+    }
+
+    fn stub_provider(idp: SharedIdp) -> (test::TestServer, OidcProviders) {
+        let shared = idp.clone();
+        let srv = test::start(move || {
+            App::new()
+                .app_data(shared.clone())
+                .route("/.well-known/openid-configuration", web::get().to(discovery))
+                .route("/jwks", web::get().to(jwks))
+                .route("/token", web::post().to(token))
+        });
+        let issuer = srv.url("/").trim_end_matches('/').to_string();
+        idp.lock().unwrap().issuer = issuer.clone();
+
+        let provider = OidcProvider {
+            name: "stub".to_string(),
+            issuer,
+            client_id: CLIENT_ID.to_string(),
+            client_secret: None,
+            redirect_uri: REDIRECT_URI.to_string(),
+        };
+        (srv, std::iter::once(provider).collect())
+    }
+
+    /// Plays the provider's consent page: grants a code for the request in
+    /// `location` and returns it with the state to send back.
+    fn authorize(idp: &SharedIdp, location: &str) -> (String, String) {
+        let query = location.split_once('?').unwrap().1;
+        let params: HashMap<String, String> = serde_urlencoded::from_str(query).unwrap();
+        assert_eq!(params["client_id"], CLIENT_ID);
+        assert_eq!(params["redirect_uri"], REDIRECT_URI);
+        assert_eq!(params["code_challenge_method"], "S256");
+
+        let code = CryptoService::generate_token(16);
+        idp.lock().unwrap().codes.insert(
+            code.clone(),
+            (params["code_challenge"].clone(), params["nonce"].clone()),
+        );
+        (code, params["state"].clone())
+    }
+
+    macro_rules! app {
+        ($db:expr, $providers:expr) => {{
+            if std::env::var("jwt_secret").is_err() {
+                std::env::set_var("jwt_secret", "oidc-test-secret");
+            }
+            test::init_service(
+                App::new()
+                    .data($db.clone())
+                    .data(JwtKeys::from_env())
+                    .data(Settings::from_env())
+                    .data($providers.clone())
+                    .service(get_oidc_login)
+                    .service(get_oidc_callback),
+            )
+            .await
+        }};
+    }
+
+    /// Starts a login and returns the redirect target and the binding cookie.
+    macro_rules! start_login {
+        ($app:expr) => {{
+            let req = test::TestRequest::get().uri("/auth/oidc/stub").to_request();
+            let res = test::call_service(&mut $app, req).await;
+            assert_eq!(res.status(), StatusCode::FOUND);
+            let location = res.headers().get(header::LOCATION).unwrap().to_str().unwrap().to_string();
+            let binding = res
+                .response()
+                .cookies()
+                .find(|cookie| cookie.name() == BINDING_COOKIE)
+                .expect("login sets the binding cookie")
+                .value()
+                .to_string();
+            (location, binding)
+        }};
+    }
+
+    macro_rules! callback {
+        ($app:expr, $code:expr, $state:expr, $binding:expr) => {{
+            let mut req = test::TestRequest::get().uri(
+                format!("/auth/oidc/stub/callback?code={}&state={}", $code, $state).as_str(),
+            );
+            if let Some(binding) = $binding {
+                req = req.cookie(Cookie::new(BINDING_COOKIE, binding));
+            }
+            test::call_service(&mut $app, req.to_request()).await
+        }};
+    }
+
+    fn idp(email: &str, email_verified: bool) -> SharedIdp {
+        web::Data::new(Mutex::new(Idp {
+            subject: CryptoService::generate_token(8),
+            email: email.to_string(),
+            email_verified,
+            ..Idp::default()
+        }))
+    }
+
+    #[actix_rt::test]
+    async fn first_login_creates_an_account() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let idp = idp("new@example.com", true);
+        let (_srv, providers) = stub_provider(idp.clone());
+        let mut app = app!(db, providers);
+
+        let (location, binding) = start_login!(app);
+        assert!(location.starts_with(&format!("{}/authorize?", idp.lock().unwrap().issuer)));
+        let (code, state) = authorize(&idp, location.as_str());
+        let res = callback!(app, code, state, Some(binding.as_str()));
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert!(body["jwt"].is_string());
+        assert_eq!(body["username"], "stubby");
+
+        let user = db
+            .collection("users")
+            .find_one(doc! { "email": "new@example.com" }, None)
+            .await
+            .unwrap()
+            .expect("account created");
+        let subject = idp.lock().unwrap().subject.clone();
+        assert_eq!(
+            user.get_array("identities").unwrap()[0]
+                .as_document()
+                .unwrap()
+                .get_str("subject")
+                .unwrap(),
+            subject
+        );
+        assert!(user.get_bool("email_verified").unwrap());
+
+        // The state was spent on the first callback.
+        let (code, _) = authorize(&idp, location.as_str());
+        let res = callback!(app, code, state, Some(binding.as_str()));
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        db.drop(None).await.unwrap();
+    }
+
+    /// Saves a password account for `email` as a local sign-up would.
+    async fn local_account(db: &Database, email: &str, email_verified: bool) -> User {
+        let mut user = User {
+            id: None,
+            username: "existing".to_string(),
+            email: email.to_string(),
+            password: "correct horse battery staple".to_string(),
+            user_avatar: None,
+            role: Default::default(),
+            token_version: 0,
+            email_verified,
+            pending_email: None,
+            totp_enabled: false,
+            totp_secret: None,
+            recovery_codes: Vec::new(),
+            status: Default::default(),
+            suspended_until: None,
+            identities: Vec::new(),
+        };
+        user.save(db, &crypto::HashConfig::from_env()).await.unwrap();
+        user
+    }
+
+    #[actix_rt::test]
+    async fn verified_email_links_the_existing_account() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let existing = local_account(&db, "linked@example.com", true).await;
+
+        let idp = idp("linked@example.com", false);
+        let (_srv, providers) = stub_provider(idp.clone());
+        let mut app = app!(db, providers);
+
+        // An unverified address at the provider must not take the account over.
+        let (location, binding) = start_login!(app);
+        let (code, state) = authorize(&idp, location.as_str());
+        let res = callback!(app, code, state, Some(binding.as_str()));
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        idp.lock().unwrap().email_verified = true;
+        let (location, binding) = start_login!(app);
+        let (code, state) = authorize(&idp, location.as_str());
+        let res = callback!(app, code, state, Some(binding.as_str()));
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["username"], "existing");
+
+        let users = db.collection("users");
+        assert_eq!(users.count_documents(None, None).await.unwrap(), 1);
+        let user = users
+            .find_one(doc! { "_id": existing.id.as_ref().unwrap() }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.get_array("identities").unwrap().len(), 1);
+        assert!(user.get_bool("email_verified").unwrap());
+        db.drop(None).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn unverified_account_is_not_linked() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        // Someone registered the victim's address and chose the password.
+        let existing = local_account(&db, "victim@example.com", false).await;
+
+        let idp = idp("victim@example.com", true);
+        let (_srv, providers) = stub_provider(idp.clone());
+        let mut app = app!(db, providers);
+
+        let (location, binding) = start_login!(app);
+        let (code, state) = authorize(&idp, location.as_str());
+        let res = callback!(app, code, state, Some(binding.as_str()));
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert!(body["jwt"].is_null());
+
+        let users = db.collection("users");
+        assert_eq!(users.count_documents(None, None).await.unwrap(), 1);
+        let user = users
+            .find_one(doc! { "_id": existing.id.as_ref().unwrap() }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(user.get_array("identities").unwrap().is_empty());
+        assert!(!user.get_bool("email_verified").unwrap());
+        db.drop(None).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn callback_checks_state_binding_and_nonce() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let idp = idp("checks@example.com", true);
+        let (_srv, providers) = stub_provider(idp.clone());
+        let mut app = app!(db, providers);
+
+        let (location, binding) = start_login!(app);
+        let (code, state) = authorize(&idp, location.as_str());
+
+        let res = callback!(app, code, "unknown", Some(binding.as_str()));
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        // Without the cookie, or with another browser's, the state is useless.
+        let res = callback!(app, code, state, None::<&str>);
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = callback!(app, code, state, Some("another-browser"));
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        idp.lock().unwrap().wrong_nonce = true;
+        let res = callback!(app, code, state, Some(binding.as_str()));
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            db.collection("users")
+                .count_documents(None, None)
+                .await
+                .unwrap(),
+            0
+        );
+        db.drop(None).await.unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+use actix_web::{delete, get, web, HttpResponse};
+use mongodb::Database;
+use serde_json::json;
+
+use crate::{
+    errors::AppError,
+    middlewares::AuthenticatedUser,
+    models::{session::Session, user::User},
+};
+
+#[get("/sessions")]
+pub async fn get_sessions(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let sessions = Session::get_active_by_user(db.get_ref(), user.user_id.as_str()).await?;
+
+    let res: Vec<_> = sessions
+        .iter()
+        .map(|session| {
+            let id = session.id.as_ref().unwrap().to_hex();
+            json!({
+                "_id": id,
+                "user_agent": session.user_agent,
+                "ip": session.ip,
+                "created_at": session.created_at,
+                "last_seen_at": session.last_seen_at,
+                "current": id == user.session_id
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[delete("/sessions/{session_id}")]
+pub async fn delete_session(
+    db: web::Data<Database>,
+    session_id: web::Path<String>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    Session::revoke(db.get_ref(), user.user_id.as_str(), session_id.as_str()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200
+    })))
+}
+
+/// Signs the caller out everywhere: ends every session and revokes their
+/// API keys. Changing or resetting the password does the same.
+#[delete("/sessions")]
+pub async fn delete_sessions(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    User::revoke_tokens(db.get_ref(), user.user_id.as_str()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200
+    })))
+}
@@ -0,0 +1,96 @@
+use actix_web::{delete, post, web, HttpResponse};
+use mongodb::Database;
+use serde_json::json;
+
+use crate::{
+    config::{
+        jwt::JwtKeys,
+        totp::{Totp, TotpKey},
+        Settings,
+    },
+    errors::{AppError, AppErrorType},
+    middlewares::{AuthenticatedUser, ClientInfo},
+    models::{
+        login_attempt::LoginGuard,
+        user::{DisableTwoFactor, TotpCode, User},
+    },
+};
+
+/// Generates a new TOTP secret for the caller. Two-factor is not enforced
+/// until a code from it is confirmed through `/user/2fa/enable`.
+#[post("/user/2fa")]
+pub async fn post_two_factor_setup(
+    db: web::Data<Database>,
+    keys: web::Data<JwtKeys>,
+    totp_key: web::Data<TotpKey>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let account = User::get_user_by_id(db.get_ref(), user.user_id.as_str()).await?;
+    let secret = User::start_totp(db.get_ref(), totp_key.get_ref(), user.user_id.as_str()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "secret": secret,
+        "otpauth_uri": Totp::uri(secret.as_str(), account.email.as_str(), keys.issuer.as_str())
+    })))
+}
+
+#[post("/user/2fa/enable")]
+pub async fn post_two_factor_enable(
+    db: web::Data<Database>,
+    totp_key: web::Data<TotpKey>,
+    data: web::Json<TotpCode>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let recovery_codes = User::enable_totp(
+        db.get_ref(),
+        totp_key.get_ref(),
+        user.user_id.as_str(),
+        data.code.trim(),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200,
+        "recovery_codes": recovery_codes
+    })))
+}
+
+/// Turns two-factor off. Wrong codes count towards the sign-in lockout,
+/// so a stolen access token cannot be used to guess one.
+#[delete("/user/2fa")]
+pub async fn delete_two_factor(
+    db: web::Data<Database>,
+    settings: web::Data<Settings>,
+    totp_key: web::Data<TotpKey>,
+    data: web::Json<DisableTwoFactor>,
+    user: AuthenticatedUser,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    let account = user
+        .reauthenticate(
+            db.get_ref(),
+            settings.reauth_window,
+            data.current_password.as_deref(),
+            &client,
+        )
+        .await?;
+    if account.totp_enabled {
+        let guard = LoginGuard::new(account.id.as_ref(), client.ip.as_deref());
+        guard.check(db.get_ref()).await?;
+        match User::verify_second_factor(db.get_ref(), totp_key.get_ref(), &account, data.code.as_str()).await {
+            Ok(()) => guard.succeeded(db.get_ref()).await?,
+            Err(_e @ AppError { error_type: AppErrorType::InvalidToken, .. }) => {
+                guard.failed(db.get_ref()).await?;
+                return Err(_e);
+            }
+            Err(_e) => return Err(_e),
+        }
+    }
+    User::disable_totp(db.get_ref(), user.user_id.as_str()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "Status": "OK",
+        "response": 200
+    })))
+}
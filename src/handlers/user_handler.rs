@@ -1,37 +1,66 @@
 use actix_multipart::Multipart;
-use actix_web::{get, patch, post, web, HttpResponse};
+use actix_web::{delete, get, http::header, patch, post, web, HttpResponse};
+use bson::oid::ObjectId;
 use mongodb::Database;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::{Arc, Mutex};
-use rand::{Rng};
 
-use crate::{config::email_client::Emailer, config::s3_aws, errors::AppError, errors::AppErrorType, models::user::Email, models::user::UserCreds};
-use crate::{models::user::PatchUser, models::user::User, AppData};
+use crate::{config::Settings, config::email_client::Emailer, config::jwt::{EmailClaims, JwtKeys}, config::s3_aws, errors::AppError, errors::AppErrorType, models::user::Email};
+use crate::models::audit_log::{AuditAction, AuditEvent};
+use crate::models::export::AccountExport;
+use crate::models::login_attempt::LoginGuard;
+use crate::models::password_reset::{invalid_token, CheckRecovery, PasswordReset, ResetPassword};
+use crate::{middlewares::AuthenticatedUser, middlewares::ClientInfo, models::user::PatchUser, models::user::DeleteAccount, models::user::SignUp, models::user::User};
 
 #[post("/user")]
 pub async fn post_user(
     db: web::Data<Database>,
+    keys: web::Data<JwtKeys>,
+    settings: web::Data<Settings>,
     mut payload: Multipart,
 ) -> Result<HttpResponse, AppError> {
-    let (data, file) = s3_aws::split_payload(&mut payload).await;
+    let (data, file) = s3_aws::split_payload(&mut payload).await?;
 
-    let _bucket = s3_aws::get_s3_bucket().await;
-    let mut user: User = serde_json::from_slice(&data).unwrap();
+    let sign_up: SignUp = serde_json::from_slice(&data).map_err(|_e| {
+        AppError::invalid_field("data", "MALFORMED_REQUEST", _e.to_string().as_str())
+    })?;
+    let mut user = User::from(sign_up);
 
-    let ext: Vec<&str> = file[0].name.split(".").collect();
+    let avatar = file.first().ok_or_else(|| {
+        AppError::invalid_field("avatar", "AVATAR_REQUIRED", "Attach an avatar image")
+    })?;
+    let ext = std::path::Path::new(avatar.name.as_str())
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .ok_or_else(|| {
+            AppError::invalid_field("avatar", "AVATAR_EXTENSION", "The avatar file needs an extension")
+        })?;
 
-    let filename = format!("{}.{}", user.username, ext[1]);
+    // A fresh key per upload: the cleanup below must never hit an object
+    // that another account got to first.
+    let filename = format!("{}{}.{}", s3_aws::AVATAR_PREFIX, ObjectId::new().to_hex(), ext);
 
     user.check_username(db.get_ref()).await?;
     user.check_email(db.get_ref()).await?;
+    settings
+        .password_policy
+        .check(user.password.as_str(), user.username.as_str(), user.email.as_str())
+        .await?;
 
-    match s3_aws::upload_file(_bucket, file[0].tmp_path.as_str(), filename.as_str()).await {
+    let _bucket = s3_aws::get_s3_bucket().await;
+    match s3_aws::upload_file(_bucket, avatar.tmp_path.as_str(), filename.as_str()).await {
         Ok(link) => {
-            s3_aws::remove_file(&file[0].tmp_path[..]);
+            s3_aws::remove_file(&avatar.tmp_path[..]);
 
-            user.user_avatar = Some(link);
-            user.save(db.get_ref()).await?;
+            user.user_avatar = Some(format!("{}{}", s3_aws::PUBLIC_URL, link));
+            if let Err(_e) = user.save(db.get_ref(), &settings.hash_config).await {
+                // No account points at the upload, so it would never be cleaned up.
+                s3_aws::delete_file(s3_aws::get_s3_bucket().await, link.as_str())
+                    .await
+                    .ok();
+                return Err(_e);
+            }
+            send_verification(keys.get_ref(), &user.id.as_ref().unwrap().to_hex(), user.email.as_str()).await;
 
             Ok(HttpResponse::Ok().json(json!({
                 "response": 200,
@@ -49,17 +78,44 @@ pub async fn post_user(
 #[derive(Serialize, Deserialize)]
 pub struct Password {
     pub password: String,
+    #[serde(default)]
+    pub current_password: Option<String>,
 }
 
 #[patch("/user-password")]
 pub async fn patch_password(
     db: web::Data<Database>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
+    settings: web::Data<Settings>,
+    user: AuthenticatedUser,
     password: web::Json<Password>,
+    client: ClientInfo,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
+    let account = user
+        .reauthenticate(
+            db.get_ref(),
+            settings.reauth_window,
+            password.current_password.as_deref(),
+            &client,
+        )
+        .await?;
+    settings
+        .password_policy
+        .check(password.password.as_str(), account.username.as_str(), account.email.as_str())
+        .await?;
 
-    User::change_password(db.get_ref(), user_id.as_str(), password.password.as_str()).await?;
+    User::change_password(
+        db.get_ref(),
+        &settings.hash_config,
+        user.user_id.as_str(),
+        password.password.as_str(),
+    )
+    .await?;
+    AuditEvent::new(AuditAction::PasswordChanged, &client)
+        .actor(user.user_id.as_str())
+        .target(user.user_id.as_str())
+        .record(db.get_ref())
+        .await;
+    notify_owner(account.email, "The password for your account was changed").await;
     Ok(HttpResponse::Ok().json(json! ({
         "Status": "OK",
         "response": 200
@@ -69,13 +125,39 @@ pub async fn patch_password(
 #[patch("/user")]
 pub async fn patch_user(
     db: web::Data<Database>,
-    app_data: web::Data<Arc<Mutex<AppData>>>,
+    keys: web::Data<JwtKeys>,
+    settings: web::Data<Settings>,
+    user: AuthenticatedUser,
     data: web::Json<PatchUser>,
+    client: ClientInfo,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = app_data.lock().unwrap().user_id.as_ref().unwrap().clone();
+    let account = User::get_user_by_id(db.get_ref(), user.user_id.as_str()).await?;
+    if account.email != data.email {
+        user.reauthenticate(
+            db.get_ref(),
+            settings.reauth_window,
+            data.current_password.as_deref(),
+            &client,
+        )
+        .await?;
+    }
 
-    data.patch_user_details(db.get_ref(), user_id.as_str())
+    let email_pending = data.patch_user_details(db.get_ref(), user.user_id.as_str())
         .await?;
+    if email_pending {
+        AuditEvent::new(AuditAction::EmailChangeRequested, &client)
+            .actor(user.user_id.as_str())
+            .target(user.user_id.as_str())
+            .detail(data.email.as_str())
+            .record(db.get_ref())
+            .await;
+        send_verification(keys.get_ref(), user.user_id.as_str(), data.email.as_str()).await;
+        notify_owner(
+            account.email,
+            format!("A change of your account email to {} was requested", data.email).as_str(),
+        )
+        .await;
+    }
 
     Ok(HttpResponse::Ok().json(json! ({
         "Status": "OK",
@@ -87,31 +169,94 @@ pub async fn patch_user(
 
 #[post("/forget-password")]
 pub async fn forget_password(db: web::Data<Database>,data: web::Json<Email>) -> Result<HttpResponse, AppError>{
-    let mailer = Emailer::from_defaults();
-    let mut val = rand::thread_rng();
-    let rec = val.gen_range(1000, 9999);
-    User::add_recovery(db.get_ref(), data.email.as_str(), rec).await?;
-    mailer.new_service(data.email.clone(), rec).await?;
+    // The lookup and the mail happen after answering, so neither the response
+    // nor how long it takes tells whether the address is registered.
+    let email = data.into_inner().email;
+    actix_rt::spawn(async move {
+        if let Err(_e) = send_recovery_code(db.get_ref(), email.as_str()).await {
+            println!("{:?}", _e);
+        }
+    });
     Ok(HttpResponse::Ok().json(json! ({
         "Status": "OK",
         "response": 200
     })))
 }
 
+async fn send_recovery_code(db: &Database, email: &str) -> Result<(), AppError> {
+    let user = match User::get_user_by_email(db, email).await {
+        Ok(user) => user,
+        Err(AppError { error_type: AppErrorType::NotFoundError, .. }) => return Ok(()),
+        Err(_e) => return Err(_e),
+    };
+    let token = PasswordReset::create(db, user.id.as_ref().unwrap()).await?;
+    Emailer::from_defaults().new_service(user.email, token.as_str()).await
+}
+
 #[post("/password")]
-pub async fn  forget_success(db: web::Data<Database>, data: web::Json<UserCreds>) -> Result<HttpResponse, AppError>{
-    User::change_password_email(db.get_ref(), data.email.as_str(), data.password.as_str()).await?;
+pub async fn  forget_success(db: web::Data<Database>, settings: web::Data<Settings>, data: web::Json<ResetPassword>, client: ClientInfo) -> Result<HttpResponse, AppError>{
+    let guard = LoginGuard::new(None, client.ip.as_deref());
+    guard.check(db.get_ref()).await?;
+
+    let user = match User::get_user_by_email(db.get_ref(), data.email.as_str()).await {
+        Ok(user) => user,
+        Err(_) => {
+            guard.failed(db.get_ref()).await?;
+            return Err(invalid_token());
+        }
+    };
+    let user_id = user.id.as_ref().unwrap();
+    // The token is proven before the password is judged, so only its holder
+    // can tell a registered address from an unknown one. It is spent after,
+    // so a rejected password can be retried.
+    if let Err(_e) = PasswordReset::verify(db.get_ref(), user_id, data.token.as_str()).await {
+        guard.failed(db.get_ref()).await?;
+        return Err(_e);
+    }
+    settings
+        .password_policy
+        .check(data.password.as_str(), user.username.as_str(), user.email.as_str())
+        .await?;
+
+    if let Err(_e) = PasswordReset::consume(db.get_ref(), user_id, data.token.as_str()).await {
+        guard.failed(db.get_ref()).await?;
+        return Err(_e);
+    }
+    User::change_password(
+        db.get_ref(),
+        &settings.hash_config,
+        user_id.to_hex().as_str(),
+        data.password.as_str(),
+    )
+    .await?;
+    AuditEvent::new(AuditAction::PasswordReset, &client)
+        .actor(user_id.to_hex().as_str())
+        .target(user_id.to_hex().as_str())
+        .record(db.get_ref())
+        .await;
+    notify_owner(user.email, "The password for your account was reset").await;
     Ok(HttpResponse::Ok().json(json! ({
         "Status": "OK",
         "response": 200
     })))
 }
 
-#[get("/forget-password/{email}/{code}")]
-pub async fn check_recovery(db: web::Data<Database>, data: web::Path<(String, i32)>) -> Result<HttpResponse, AppError>{
-    let (email, code) = data.into_inner();
+#[post("/forget-password/verify")]
+pub async fn check_recovery(db: web::Data<Database>, data: web::Json<CheckRecovery>, client: ClientInfo) -> Result<HttpResponse, AppError>{
+    let guard = LoginGuard::new(None, client.ip.as_deref());
+    guard.check(db.get_ref()).await?;
 
-    User::check_validiity(db.get_ref(), email.as_str(), code).await?;
+    let user = match User::get_user_by_email(db.get_ref(), data.email.as_str()).await {
+        Ok(user) => user,
+        Err(_) => {
+            guard.failed(db.get_ref()).await?;
+            return Err(invalid_token());
+        }
+    };
+    if let Err(_e) = PasswordReset::verify(db.get_ref(), user.id.as_ref().unwrap(), data.token.as_str()).await {
+        guard.failed(db.get_ref()).await?;
+        return Err(_e);
+    }
 
     Ok(HttpResponse::Ok().json(json! ({
         "Status": "OK",
@@ -119,11 +264,118 @@ pub async fn check_recovery(db: web::Data<Database>, data: web::Path<(String, i3
     })))
 }
 
-//#[get("/users")]
-//pub async fn get_users(db: web::Data<Database>) -> Result<HttpResponse, AppError> {
-//    let users = User::get_users(db.get_ref()).await?;
-//    Ok(HttpResponse::Ok().json(users))
-//}
+/// Mails a confirmation link for `email`. Delivery failures are logged rather
+/// than failing the request; the user can ask for another link.
+async fn send_verification(keys: &JwtKeys, user_id: &str, email: &str) {
+    let sent = match EmailClaims::encode_req(keys, user_id, email) {
+        Ok(token) => Emailer::from_defaults().send_verification(email.to_string(), token.as_str()).await,
+        Err(_e) => Err(_e),
+    };
+    if let Err(_e) = sent {
+        println!("{:?}", _e);
+    }
+}
+
+/// Tells the owner about a sensitive change to their account. Delivery
+/// failures are only logged, the change stands either way.
+async fn notify_owner(email: String, change: &str) {
+    if let Err(_e) = Emailer::from_defaults().send_security_notice(email, change).await {
+        println!("{:?}", _e);
+    }
+}
+
+#[get("/verify-email/{token}")]
+pub async fn verify_email(db: web::Data<Database>, keys: web::Data<JwtKeys>, token: web::Path<String>, client: ClientInfo) -> Result<HttpResponse, AppError>{
+    let claims = EmailClaims::decode_req(keys.get_ref(), token.as_str())?;
+    let previous = User::get_user_by_id(db.get_ref(), claims.sub.as_str()).await?;
+    User::verify_email(db.get_ref(), claims.sub.as_str(), claims.email.as_str()).await?;
+    if previous.email != claims.email {
+        AuditEvent::new(AuditAction::EmailChanged, &client)
+            .actor(claims.sub.as_str())
+            .target(claims.sub.as_str())
+            .detail(format!("{} -> {}", previous.email, claims.email).as_str())
+            .record(db.get_ref())
+            .await;
+    }
+
+    Ok(HttpResponse::Ok().json(json! ({
+        "Status": "OK",
+        "response": 200
+    })))
+}
+
+#[post("/verify-email")]
+pub async fn resend_verification(db: web::Data<Database>, keys: web::Data<JwtKeys>, user: AuthenticatedUser) -> Result<HttpResponse, AppError>{
+    let account = User::get_user_by_id(db.get_ref(), user.user_id.as_str()).await?;
+
+    match account.pending_email {
+        Some(email) => send_verification(keys.get_ref(), user.user_id.as_str(), email.as_str()).await,
+        None if !account.email_verified => send_verification(keys.get_ref(), user.user_id.as_str(), account.email.as_str()).await,
+        None => {}
+    }
+
+    Ok(HttpResponse::Ok().json(json! ({
+        "Status": "OK",
+        "response": 200
+    })))
+}
+
+/// Downloads everything stored about the caller as a ZIP of JSON files plus
+/// their uploaded avatar.
+#[get("/user/export")]
+pub async fn get_export(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let export = AccountExport::collect(db.get_ref(), user.user_id.as_str()).await?;
+    let avatar = match export.profile.user_avatar.as_deref().and_then(s3_aws::object_path) {
+        Some(path) => {
+            let content = s3_aws::download_file(s3_aws::get_s3_bucket().await, path).await?;
+            Some((path.trim_start_matches('/').to_string(), content))
+        }
+        None => None,
+    };
+    let archive = export.into_zip(avatar).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .header(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"account-export.zip\"",
+        )
+        .body(archive))
+}
+
+#[delete("/user")]
+pub async fn delete_user(
+    db: web::Data<Database>,
+    settings: web::Data<Settings>,
+    user: AuthenticatedUser,
+    data: web::Json<DeleteAccount>,
+    client: ClientInfo,
+) -> Result<HttpResponse, AppError> {
+    let account = user
+        .reauthenticate(
+            db.get_ref(),
+            settings.reauth_window,
+            data.current_password.as_deref(),
+            &client,
+        )
+        .await?;
+
+    User::delete_account(db.get_ref(), &account, data.mode).await?;
+    AuditEvent::new(AuditAction::AccountDeleted, &client)
+        .actor(user.user_id.as_str())
+        .target(user.user_id.as_str())
+        .detail(format!("{:?}", data.mode).as_str())
+        .record(db.get_ref())
+        .await;
+    notify_owner(account.email, "Your account and its data were deleted").await;
+    Ok(HttpResponse::Ok().json(json! ({
+        "Status": "OK",
+        "response": 200
+    })))
+}
 
 #[get("/user/{uid}")]
 pub async fn get_user(
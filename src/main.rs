@@ -3,7 +3,6 @@ use actix_web::{middleware, web, App, HttpServer};
 use env_logger::Env;
 use errors::AppError;
 use listenfd::ListenFd;
-use std::sync::{Arc, Mutex};
 
 #[allow(dead_code)]
 mod config;
@@ -16,20 +15,13 @@ mod middlewares;
 #[allow(dead_code)]
 mod models;
 
-use crate::middlewares::CheckAuth;
+use crate::middlewares::{
+    rate_limit::{RateLimit, RateLimitStore},
+    CheckAuth,
+};
 use config::Config;
-use handlers::configure;
-
-#[derive(Debug)]
-pub struct AppData {
-    pub user_id: Option<String>,
-}
-
-impl AppData {
-    fn new() -> Self {
-        AppData { user_id: None }
-    }
-}
+use models::user::User;
+use handlers::{api_key_scopes, configure, rate_limit_groups};
 
 #[allow(unused_must_use)]
 #[actix_rt::main]
@@ -39,15 +31,28 @@ async fn main() -> Result<(), AppError> {
     let config = Config::from_env();
 
     let db = config.get_db().await?;
-    let app_data = web::Data::new(Arc::new(Mutex::new(AppData::new())));
+    User::create_indexes(&db).await?;
+    let jwt_keys = config.jwt.clone();
+    let settings = config.settings.clone();
+    let oidc = config.oidc.clone();
+    let totp_key = config.totp_key.clone();
+    let rate_limit_store = RateLimitStore::from_env(&db).await?;
 
     let mut server = HttpServer::new(move || {
         App::new()
             .wrap(Cors::default())
-            .app_data(app_data.clone())
+            .wrap(RateLimit::new(rate_limit_store.clone(), rate_limit_groups()))
             .wrap(CheckAuth)
             .wrap(middleware::Logger::new("%a %r %s %Ts"))
             .data(db.clone())
+            .data(jwt_keys.clone())
+            .data(settings.clone())
+            .data(oidc.clone())
+            .data(totp_key.clone())
+            .data(api_key_scopes())
+            .app_data(web::JsonConfig::default().error_handler(errors::json_error))
+            .app_data(web::QueryConfig::default().error_handler(errors::query_error))
+            .app_data(web::PathConfig::default().error_handler(errors::path_error))
             .configure(configure)
     });
 
@@ -0,0 +1,200 @@
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ResourceDef, ServiceRequest, ServiceResponse},
+    http::Method,
+    Error, HttpMessage,
+};
+use chrono::Utc;
+use futures::future::{ok, Either, Ready};
+use mongodb::Database;
+
+use crate::{
+    config::crypto::CryptoService,
+    errors::{AppError, AppErrorType},
+    middlewares::{AuthenticatedUser, ClientInfo},
+    models::{
+        api_key::Scope,
+        blogs::{BlogPost, Comments},
+        login_attempt::LoginGuard,
+        user::{Role, User, UserDetails},
+    },
+};
+
+/// Content a caller is trying to modify, identified by its path ids.
+pub enum Target<'a> {
+    Post(&'a str),
+    Comment(&'a str),
+    Reply(&'a str, &'a str),
+}
+
+impl AuthenticatedUser {
+    /// Succeeds when the caller authored `target` or holds a moderating role.
+    pub async fn authorize(&self, db: &Database, target: Target<'_>) -> Result<(), AppError> {
+        let owner = match target {
+            Target::Post(blog_id) => BlogPost::get_post_by_id(db, blog_id).await?.user_id,
+            Target::Comment(comment_id) => Some(
+                Comments::get_comments_by_id(db, comment_id)
+                    .await?
+                    .user_id
+                    .to_hex(),
+            ),
+            Target::Reply(comment_id, reply_id) => Some(
+                Comments::get_comments_by_id(db, comment_id)
+                    .await?
+                    .get_reply(reply_id)?
+                    .user_id
+                    .to_hex(),
+            ),
+        };
+
+        if owner.as_deref() == Some(self.user_id.as_str()) {
+            return Ok(());
+        }
+
+        if self.role.can_moderate() {
+            return Ok(());
+        }
+
+        Err(AppError {
+            cause: Some("NOT_OWNER".to_string()),
+            message: Some("You are not allowed to modify this resource".to_string()),
+            error_type: AppErrorType::ForbiddenError,
+        })
+    }
+
+    /// Gate for password, email and account deletion changes, so a stolen
+    /// access token alone cannot take the account over. Passes when the
+    /// session signed in within `window` seconds or `current_password` is
+    /// right; wrong passwords count towards the sign-in lockout.
+    pub async fn reauthenticate(
+        &self,
+        db: &Database,
+        window: usize,
+        current_password: Option<&str>,
+        client: &ClientInfo,
+    ) -> Result<UserDetails, AppError> {
+        let account = User::get_user_by_id(db, self.user_id.as_str()).await?;
+
+        let password = match current_password {
+            Some(password) => password,
+            None => {
+                let now = Utc::now().timestamp() as usize;
+                return match self.auth_time {
+                    Some(auth_time) if auth_time + window >= now => Ok(account),
+                    _ => Err(AppError {
+                        cause: Some("REAUTHENTICATION_REQUIRED".to_string()),
+                        message: Some(
+                            "Confirm your current password or sign in again".to_string(),
+                        ),
+                        error_type: AppErrorType::ForbiddenError,
+                    }),
+                };
+            }
+        };
+
+        let guard = LoginGuard::new(account.id.as_ref(), client.ip.as_deref());
+        guard.check(db).await?;
+        if !CryptoService::verify_hash(account.password.clone(), password.to_string()).await? {
+            guard.failed(db).await?;
+            return Err(AppError {
+                cause: Some("INCORRECT_PASSWORD".to_string()),
+                message: Some("Current password is incorrect".to_string()),
+                error_type: AppErrorType::ForbiddenError,
+            });
+        }
+        guard.succeeded(db).await?;
+        Ok(account)
+    }
+}
+
+/// Route guard admitting only callers whose role is at least the given one.
+/// Anonymous callers get a 401, authenticated ones without the role a 403.
+///
+/// `web::scope("/admin").wrap(RequireRole(Role::Admin))`
+pub struct RequireRole(pub Role);
+
+impl<S, B> Transform<S> for RequireRole
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireRoleMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequireRoleMiddleware {
+            service,
+            role: self.0,
+        })
+    }
+}
+
+pub struct RequireRoleMiddleware<S> {
+    service: S,
+    role: Role,
+}
+
+impl<S, B> Service for RequireRoleMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let role = req
+            .extensions()
+            .get::<AuthenticatedUser>()
+            .map(|user| user.role);
+
+        let error = match role {
+            Some(role) if role >= self.role => return Either::Left(self.service.call(req)),
+            Some(_) => AppError {
+                cause: Some("INSUFFICIENT_ROLE".to_string()),
+                message: Some("You are not allowed to access this resource".to_string()),
+                error_type: AppErrorType::ForbiddenError,
+            },
+            None => AppError {
+                cause: Some("No Jwt token Attached".to_string()),
+                message: Some("Add the JWT token Header".to_string()),
+                error_type: AppErrorType::JWtTokenError,
+            },
+        };
+        Either::Right(ok(req.error_response(error)))
+    }
+}
+
+/// Scope an API key needs for each route it may call, registered as app data
+/// and consulted by `CheckAuth`.
+#[derive(Default)]
+pub struct ApiKeyScopes {
+    routes: Vec<(Method, ResourceDef, Scope)>,
+}
+
+impl ApiKeyScopes {
+    /// Adds a route, written the same way as in the handler attribute.
+    pub fn route(mut self, method: Method, path: &str, scope: Scope) -> Self {
+        self.routes.push((method, ResourceDef::new(path), scope));
+        self
+    }
+
+    pub fn required(&self, req: &ServiceRequest) -> Option<Scope> {
+        self.routes
+            .iter()
+            .find(|(method, path, _)| method == req.method() && path.is_match(req.path()))
+            .map(|(_, _, scope)| *scope)
+    }
+}
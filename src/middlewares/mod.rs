@@ -1,22 +1,133 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::cell::RefCell;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
 use std::task::{Context, Poll};
 
 use actix_service::{Service, Transform};
 use actix_web::{
-    dev::{ServiceRequest, ServiceResponse},
-    web, Error,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::{header, HeaderMap},
+    web, Error, FromRequest, HttpMessage, HttpRequest,
 };
-use futures::future::{ok, Either, Ready};
+use futures::future::{err, ok, LocalBoxFuture, Ready};
+use mongodb::Database;
 
-use crate::{config::jwt::Claims, errors::AppError, errors::AppErrorType, AppData};
+use crate::{
+    config::{
+        jwt::{Claims, JwtKeys},
+        Settings,
+    },
+    errors::AppError,
+    errors::AppErrorType,
+    middlewares::authorization::ApiKeyScopes,
+    models::{
+        api_key::{ApiKey, Scope, API_KEY_PREFIX},
+        session::Session,
+        user::{Role, User},
+    },
+};
+
+pub mod authorization;
+pub mod rate_limit;
+
+/// Identity of the caller, attached to the request extensions by `CheckAuth`
+/// once the bearer token has been verified.
+///
+/// Callers using an API key have no session, so `session_id` is empty, and
+/// `scopes` lists what the key was granted. It is `None` for logins.
+/// `auth_time` is the Unix time the session signed in, `None` for API keys.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user_id: String,
+    pub session_id: String,
+    pub role: Role,
+    pub scopes: Option<Vec<Scope>>,
+    pub auth_time: Option<usize>,
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match req.extensions().get::<AuthenticatedUser>() {
+            Some(user) => ok(user.clone()),
+            None => err(AppError {
+                cause: Some("No Jwt token Attached".to_string()),
+                message: Some("Add the JWT token Header".to_string()),
+                error_type: AppErrorType::JWtTokenError,
+            }),
+        }
+    }
+}
 
+/// Address and user agent a request was sent from.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Address the request came from: the connection's peer, or what trusted
+/// proxies forwarded for it. Login throttling, sessions and the audit log
+/// rely on it, so a client must not be able to choose it.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    resolve_client_ip(
+        req.peer_addr(),
+        req.headers(),
+        req.app_data::<web::Data<Settings>>(),
+    )
+}
+
+/// `client_ip` from the parts a `ServiceRequest` can also hand out.
+pub fn resolve_client_ip(
+    peer: Option<SocketAddr>,
+    headers: &HeaderMap,
+    settings: Option<&web::Data<Settings>>,
+) -> Option<IpAddr> {
+    let peer = peer.map(|addr| addr.ip());
+    let settings = match settings {
+        Some(settings) => settings,
+        None => return peer,
+    };
+    let forwarded_for = headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    settings.client_ip(peer, Some(forwarded_for.as_str()))
+}
+
+impl FromRequest for ClientInfo {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ok(ClientInfo {
+            ip: client_ip(req).map(|ip| ip.to_string()),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|agent| agent.to_str().ok())
+                .map(|agent| agent.to_string()),
+        })
+    }
+}
+
+/// Authenticates requests that carry an `Authorization` header and attaches
+/// the caller as an `AuthenticatedUser`. It never rejects anonymous requests:
+/// a route is protected by taking `AuthenticatedUser` as an argument, or by
+/// sitting in a scope wrapped with `RequireRole`. A header that is present but
+/// malformed or invalid is always rejected with a 401.
 pub struct CheckAuth;
 
 impl<S, B> Transform<S> for CheckAuth
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
+    B: 'static,
 {
     type Request = ServiceRequest;
     type Response = ServiceResponse<B>;
@@ -26,77 +137,152 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(CheckAuthMiddleware { service })
+        ok(CheckAuthMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
     }
 }
 pub struct CheckAuthMiddleware<S> {
-    service: S,
+    service: Rc<RefCell<S>>,
+}
+
+/// Extracts the token from an `Authorization: Bearer <token>` header.
+fn bearer_token(req: &ServiceRequest) -> Result<Option<String>, AppError> {
+    let value = match req.headers().get(header::AUTHORIZATION) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let malformed = || AppError {
+        cause: Some("MALFORMED_AUTHORIZATION_HEADER".to_string()),
+        message: Some("Authorization header must be `Bearer <token>`".to_string()),
+        error_type: AppErrorType::JWtTokenError,
+    };
+
+    let mut parts = value.to_str().map_err(|_| malformed())?.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(scheme), Some(token), None) if scheme.eq_ignore_ascii_case("bearer") => {
+            Ok(Some(token.to_string()))
+        }
+        _ => Err(malformed()),
+    }
+}
+
+/// Verifies the bearer token, that neither its session nor the user's token
+/// version has been revoked since it was issued, and that the account is not
+/// suspended or banned.
+async fn authenticate(
+    token: &str,
+    keys: &JwtKeys,
+    db: &Database,
+) -> Result<AuthenticatedUser, AppError> {
+    let claims = Claims::decode_req(keys, token)
+        .map_err(|_e| AppError {
+            cause: _e.cause,
+            message: Some("Invalid or expired JWT token".to_string()),
+            error_type: AppErrorType::JWtTokenError,
+        })?
+        .claims;
+    let revoked = || AppError {
+        cause: Some("TOKEN_REVOKED".to_string()),
+        message: Some("Session has been revoked, log in again".to_string()),
+        error_type: AppErrorType::JWtTokenError,
+    };
+
+    let user = match User::get_user_by_id(db, claims.sub.as_str()).await {
+        Ok(user) => Ok(user),
+        Err(AppError {
+            error_type: AppErrorType::NotFoundError,
+            ..
+        }) => Err(revoked()),
+        Err(_e) => Err(_e),
+    }?;
+    if user.token_version != claims.ver || !Session::is_active(db, claims.sid.as_str()).await? {
+        return Err(revoked());
+    }
+    user.status.ensure_active(user.suspended_until.as_ref())?;
+
+    Ok(AuthenticatedUser {
+        user_id: claims.sub,
+        session_id: claims.sid,
+        role: user.role,
+        scopes: None,
+        auth_time: Some(claims.auth_time),
+    })
+}
+
+/// Verifies an API key, that its owner may still sign in, and that it was
+/// granted the scope the route requires. Routes without a declared scope are
+/// closed to API keys.
+async fn authenticate_api_key(
+    key: &str,
+    required: Option<Scope>,
+    db: &Database,
+) -> Result<AuthenticatedUser, AppError> {
+    let api_key = ApiKey::authenticate(db, key).await?;
+    let user = User::get_user_by_id(db, api_key.user_id.to_hex().as_str()).await?;
+    user.status.ensure_active(user.suspended_until.as_ref())?;
+
+    match required {
+        Some(scope) if api_key.scopes.contains(&scope) => Ok(AuthenticatedUser {
+            user_id: api_key.user_id.to_hex(),
+            session_id: String::new(),
+            role: user.role,
+            scopes: Some(api_key.scopes),
+            auth_time: None,
+        }),
+        _ => Err(AppError {
+            cause: Some("INSUFFICIENT_SCOPE".to_string()),
+            message: Some("This API key is not allowed to access this resource".to_string()),
+            error_type: AppErrorType::ForbiddenError,
+        }),
+    }
 }
 
 impl<S, B> Service for CheckAuthMiddleware<S>
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
+    B: 'static,
 {
     type Request = ServiceRequest;
     type Response = ServiceResponse<B>;
     type Error = Error;
-    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
+        self.service.borrow_mut().poll_ready(cx)
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        if req.method() == "OPTIONS" {
-            return Either::Left(self.service.call(req));
-        }
-
-        let mut except: HashMap<String, String> = HashMap::new();
-        except.insert("/auth/user".to_string(), "POST".to_string());
-        except.insert("/user".to_string(), "POST".to_string());
-        except.insert("/user/".to_string(), "GET".to_string());
-        except.insert("/blogs".to_string(), "GET".to_string());
-        except.insert("/blog/".to_string(), "GET".to_string());
-        except.insert("/comment/".to_string(), "GET".to_string());
-        except.insert("/password".to_string(), "POST".to_string());
-        except.insert("/forget-password".to_string(), "POST".to_string());
-        except.insert("/forget-password/".to_string(), "GET".to_string());
-
-        for (url, method) in except.iter() {
-            if req.path().contains(url.as_str()) && req.method().as_str() == method.as_str() {
-                return Either::Left(self.service.call(req));
-            }
-        }
+        let token = match bearer_token(&req) {
+            Ok(Some(token)) => token,
+            Ok(None) => return Box::pin(self.service.borrow_mut().call(req)),
+            Err(_e) => return Box::pin(ok(req.error_response(_e))),
+        };
+        let keys = req.app_data::<web::Data<JwtKeys>>().unwrap().clone();
+        let db = req.app_data::<web::Data<Database>>().unwrap().clone();
+        let required = req
+            .app_data::<web::Data<ApiKeyScopes>>()
+            .and_then(|scopes| scopes.required(&req));
+        let service = self.service.clone();
 
-        let _auth = req.headers().get("authorization");
-
-        match _auth {
-            Some(_) => {
-                let _split: Vec<&str> = _auth.unwrap().to_str().unwrap().split("Bearer").collect();
-                let token = _split[1].trim();
-                match Claims::decode_req(token) {
-                    Ok(_token) => {
-                        // let user = UserData{user_id: Some(_token.claims.sub)};
-                        {
-                            let app_data =
-                                req.app_data::<web::Data<Arc<Mutex<AppData>>>>().unwrap();
-                            let mut data = app_data.lock().unwrap();
-                            data.user_id = Some(_token.claims.sub);
-                        }
-                        return Either::Left(self.service.call(req));
-                    }
-                    Err(_e) => {
-                        println!("{:?}", _e);
-                        Either::Right(ok(req.error_response(_e)))
-                    }
+        Box::pin(async move {
+            let user = if token.starts_with(API_KEY_PREFIX) {
+                authenticate_api_key(token.as_str(), required, db.get_ref()).await
+            } else {
+                authenticate(token.as_str(), keys.get_ref(), db.get_ref()).await
+            };
+            match user {
+                Ok(user) => {
+                    req.extensions_mut().insert(user);
+                    let fut = service.borrow_mut().call(req);
+                    fut.await
+                }
+                Err(_e) => {
+                    println!("{:?}", _e);
+                    Ok(req.error_response(_e))
                 }
             }
-            None => Either::Right(ok(req.error_response(AppError {
-                cause: Some("No Jwt token Attached".to_string()),
-                message: Some("Add the JWT token Header".to_string()),
-                error_type: AppErrorType::JWtTokenError,
-            }))),
-        }
+        })
     }
 }
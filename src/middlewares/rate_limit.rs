@@ -0,0 +1,305 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env::var;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ResourceDef, ServiceRequest, ServiceResponse},
+    http::{header, HeaderValue, Method},
+    web, Error, HttpMessage,
+};
+use bson::{doc, DateTime};
+use chrono::{Duration, Utc};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use mongodb::{
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+    Database,
+};
+
+use crate::{
+    config::Settings,
+    errors::{AppError, AppErrorType},
+    middlewares::AuthenticatedUser,
+};
+
+/// Requests allowed per window for one set of routes.
+#[derive(Clone)]
+pub struct RateLimitGroup {
+    pub name: &'static str,
+    pub limit: u32,
+    pub window: i64,
+    routes: Vec<(Method, ResourceDef)>,
+}
+
+impl RateLimitGroup {
+    /// `limit` requests per `window` seconds, overridable with the env var
+    /// `rate_limit_<name>` set to `<limit>/<seconds>`, both above zero.
+    pub fn new(name: &'static str, limit: u32, window: i64) -> Self {
+        let (limit, window) = match var(format!("rate_limit_{}", name)) {
+            Ok(value) => {
+                let mut parts = value.splitn(2, '/');
+                let limit = parts
+                    .next()
+                    .and_then(|limit| limit.parse::<u32>().ok())
+                    .filter(|limit| *limit > 0);
+                let window = parts
+                    .next()
+                    .and_then(|window| window.parse::<i64>().ok())
+                    .filter(|window| *window > 0);
+                match (limit, window) {
+                    (Some(limit), Some(window)) => (limit, window),
+                    _ => panic!("rate_limit_{} must look like <limit>/<seconds>", name),
+                }
+            }
+            Err(_) => (limit, window),
+        };
+
+        RateLimitGroup {
+            name,
+            limit,
+            window,
+            routes: vec![],
+        }
+    }
+
+    /// Adds a route, written the same way as in the handler attribute.
+    pub fn route(mut self, method: Method, path: &str) -> Self {
+        self.routes.push((method, ResourceDef::new(path)));
+        self
+    }
+
+    fn matches(&self, req: &ServiceRequest) -> bool {
+        self.routes
+            .iter()
+            .any(|(method, path)| method == req.method() && path.is_match(req.path()))
+    }
+}
+
+/// Where hit counts live. `Memory` is per process; `Mongo` shares counts
+/// between instances behind a load balancer.
+#[derive(Clone)]
+pub enum RateLimitStore {
+    Memory(Arc<Mutex<HashMap<String, Counter>>>),
+    Mongo(Database),
+}
+
+/// Hits in the current window and the one before it.
+#[derive(Clone, Copy, Default)]
+pub struct Counter {
+    window: i64,
+    current: u32,
+    previous: u32,
+}
+
+/// Entries the in-memory store holds before dropping stale windows.
+const MEMORY_SWEEP_THRESHOLD: usize = 10_000;
+
+impl RateLimitStore {
+    /// Reads `rate_limit_backend`: `memory` (default) or `mongo`.
+    pub async fn from_env(db: &Database) -> Result<RateLimitStore, AppError> {
+        match var("rate_limit_backend").as_deref() {
+            Ok("mongo") => {
+                // Lets MongoDB expire finished windows on its own.
+                db.run_command(
+                    doc! {
+                        "createIndexes": "rate_limits",
+                        "indexes": [{
+                            "key": { "expires_at": 1 },
+                            "name": "expires_at_ttl",
+                            "expireAfterSeconds": 0
+                        }]
+                    },
+                    None,
+                )
+                .await
+                .map_err(AppError::database_error)?;
+                Ok(RateLimitStore::Mongo(db.clone()))
+            }
+            _ => Ok(RateLimitStore::Memory(Arc::new(Mutex::new(HashMap::new())))),
+        }
+    }
+
+    /// Records a hit and returns the counts for the current and previous
+    /// windows.
+    async fn hit(&self, key: &str, window: i64, window_secs: i64) -> Result<(u32, u32), AppError> {
+        match self {
+            RateLimitStore::Memory(counters) => {
+                let mut counters = counters.lock().unwrap();
+                if counters.len() > MEMORY_SWEEP_THRESHOLD {
+                    counters.retain(|_, counter| counter.window >= window - 1);
+                }
+
+                let counter = counters.entry(key.to_string()).or_default();
+                *counter = match window - counter.window {
+                    0 => Counter {
+                        current: counter.current + 1,
+                        ..*counter
+                    },
+                    1 => Counter {
+                        window,
+                        current: 1,
+                        previous: counter.current,
+                    },
+                    _ => Counter {
+                        window,
+                        current: 1,
+                        previous: 0,
+                    },
+                };
+                Ok((counter.current, counter.previous))
+            }
+            RateLimitStore::Mongo(db) => {
+                let coll = db.collection("rate_limits");
+                let options = FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build();
+                let expires_at = DateTime(Utc::now() + Duration::seconds(window_secs * 2));
+
+                let current = coll
+                    .find_one_and_update(
+                        doc! { "_id": format!("{}:{}", key, window) },
+                        doc! {
+                            "$inc": { "count": 1 },
+                            "$setOnInsert": { "expires_at": expires_at.0 }
+                        },
+                        options,
+                    )
+                    .await
+                    .map_err(AppError::database_error)?
+                    .and_then(|doc| doc.get_i32("count").ok())
+                    .unwrap_or(1);
+                let previous = coll
+                    .find_one(doc! { "_id": format!("{}:{}", key, window - 1) }, None)
+                    .await
+                    .map_err(AppError::database_error)?
+                    .and_then(|doc| doc.get_i32("count").ok())
+                    .unwrap_or(0);
+
+                Ok((current as u32, previous as u32))
+            }
+        }
+    }
+}
+
+/// Sliding-window rate limiter. Each request is matched against the route
+/// groups and counted per authenticated user, or per client address for
+/// anonymous callers. It must be wrapped inside `CheckAuth` so the caller is
+/// already known.
+#[derive(Clone)]
+pub struct RateLimit {
+    store: RateLimitStore,
+    groups: Rc<Vec<RateLimitGroup>>,
+}
+
+impl RateLimit {
+    pub fn new(store: RateLimitStore, groups: Vec<RateLimitGroup>) -> Self {
+        RateLimit {
+            store,
+            groups: Rc::new(groups),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            limiter: self.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    limiter: RateLimit,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let group = match self.limiter.groups.iter().find(|group| group.matches(&req)) {
+            Some(group) => group.clone(),
+            None => return Box::pin(self.service.borrow_mut().call(req)),
+        };
+        let caller = match req.extensions().get::<AuthenticatedUser>() {
+            Some(user) => format!("user:{}", user.user_id),
+            None => format!("ip:{}", client_ip(&req)),
+        };
+        let key = format!("{}:{}", group.name, caller);
+        let store = self.limiter.store.clone();
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let now = Utc::now().timestamp();
+            let window = now / group.window;
+            let elapsed = now % group.window;
+
+            let (current, previous) = match store.hit(key.as_str(), window, group.window).await {
+                Ok(counts) => counts,
+                Err(_e) => return Ok(req.error_response(_e)),
+            };
+            // Weight the previous window by how much of it still overlaps the
+            // sliding window ending now.
+            let estimate = previous as f64 * (group.window - elapsed) as f64 / group.window as f64
+                + current as f64;
+
+            if estimate <= group.limit as f64 {
+                let fut = service.borrow_mut().call(req);
+                return fut.await;
+            }
+
+            let retry_after = (group.window - elapsed).max(1);
+            let mut res = req.error_response(AppError {
+                cause: Some("RATE_LIMITED".to_string()),
+                message: Some(format!(
+                    "Too many requests, try again in {} seconds",
+                    retry_after
+                )),
+                error_type: AppErrorType::TooManyRequests,
+            });
+            res.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from_str(retry_after.to_string().as_str()).unwrap(),
+            );
+            Ok(res)
+        })
+    }
+}
+
+fn client_ip(req: &ServiceRequest) -> String {
+    super::resolve_client_ip(
+        req.peer_addr(),
+        req.headers(),
+        req.app_data::<web::Data<Settings>>(),
+    )
+        .map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
+}
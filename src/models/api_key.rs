@@ -0,0 +1,216 @@
+use bson::{doc, oid::ObjectId, DateTime};
+use chrono::{Duration, Utc};
+use futures::StreamExt;
+use mongodb::{options::FindOptions, Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::crypto::CryptoService,
+    errors::{AppError, AppErrorType},
+};
+
+/// Marks a bearer token as an API key rather than a JWT.
+pub const API_KEY_PREFIX: &str = "bk_";
+
+/// Longest lifetime a key can be given, in days.
+const MAX_EXPIRY_DAYS: i64 = 365;
+
+fn get_coll(db: &Database) -> Collection {
+    db.collection("api_keys")
+}
+
+/// What an API key may be used for. Routes declare the scope they need in
+/// `handlers::api_key_scopes`; keys cannot reach any other route.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    #[serde(rename = "posts:read")]
+    PostsRead,
+    #[serde(rename = "posts:write")]
+    PostsWrite,
+    #[serde(rename = "comments:read")]
+    CommentsRead,
+    #[serde(rename = "comments:write")]
+    CommentsWrite,
+    #[serde(rename = "votes:write")]
+    VotesWrite,
+}
+
+/// Long-lived credential for automation. Only the SHA-256 of the key is
+/// stored; `prefix` is kept so owners can tell their keys apart.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiKey {
+    #[serde(rename = "_id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime>,
+    pub revoked: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_in_days: Option<i64>,
+}
+
+async fn convert_obj_id(id: &str) -> Result<ObjectId, AppError> {
+    match ObjectId::with_string(id) {
+        Ok(val) => Ok(val),
+        Err(_e) => Err(AppError {
+            cause: Some(_e.to_string()),
+            message: None,
+            error_type: AppErrorType::InvalidId,
+        }),
+    }
+}
+
+impl ApiKey {
+    /// Creates a key for `user_id` and returns it with the plaintext key,
+    /// which is not stored and cannot be shown again.
+    pub async fn create(
+        db: &Database,
+        user_id: &str,
+        data: &NewApiKey,
+    ) -> Result<(ApiKey, String), AppError> {
+        if data.scopes.is_empty() {
+            return Err(AppError::invalid_field(
+                "scopes",
+                "SCOPES_REQUIRED",
+                "An API key needs at least one scope",
+            ));
+        }
+        let expires_at = match data.expires_in_days {
+            Some(days) => Some(
+                Some(days)
+                    .filter(|days| (1..=MAX_EXPIRY_DAYS).contains(days))
+                    .and_then(|days| Utc::now().checked_add_signed(Duration::days(days)))
+                    .map(DateTime)
+                    .ok_or_else(|| {
+                        AppError::invalid_field(
+                            "expires_in_days",
+                            "OUT_OF_RANGE",
+                            format!("Must be between 1 and {} days", MAX_EXPIRY_DAYS).as_str(),
+                        )
+                    })?,
+            ),
+            None => None,
+        };
+        let key = format!("{}{}", API_KEY_PREFIX, CryptoService::generate_token(40));
+        let mut api_key = ApiKey {
+            id: None,
+            user_id: convert_obj_id(user_id).await?,
+            name: data.name.clone(),
+            prefix: key[..API_KEY_PREFIX.len() + 8].to_string(),
+            key_hash: CryptoService::hash_token(key.as_str()),
+            scopes: data.scopes.clone(),
+            created_at: DateTime(Utc::now()),
+            expires_at,
+            last_used_at: None,
+            revoked: false,
+        };
+
+        let res = get_coll(db)
+            .insert_one(bson::to_document(&api_key).unwrap(), None)
+            .await
+            .map_err(AppError::database_error)?;
+        api_key.id = res.inserted_id.as_object_id().cloned();
+
+        Ok((api_key, key))
+    }
+
+    /// Looks up a live key by its plaintext and records that it was used.
+    pub async fn authenticate(db: &Database, key: &str) -> Result<ApiKey, AppError> {
+        let doc = get_coll(db)
+            .find_one_and_update(
+                doc! {
+                    "key_hash": CryptoService::hash_token(key),
+                    "revoked": false,
+                    "$or": [
+                        { "expires_at": { "$exists": false } },
+                        { "expires_at": { "$gt": Utc::now() } }
+                    ]
+                },
+                doc! { "$set": { "last_used_at": Utc::now() } },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        match doc {
+            Some(doc) => bson::from_document(doc).map_err(AppError::decode_error),
+            None => Err(AppError {
+                cause: Some("INVALID_API_KEY".to_string()),
+                message: Some("API key is invalid, expired or revoked".to_string()),
+                error_type: AppErrorType::JWtTokenError,
+            }),
+        }
+    }
+
+    pub async fn get_by_user(db: &Database, user_id: &str) -> Result<Vec<ApiKey>, AppError> {
+        let options = FindOptions::builder()
+            .sort(doc! {"created_at": -1 })
+            .build();
+        let mut cur = get_coll(db)
+            .find(
+                doc! {
+                    "user_id": convert_obj_id(user_id).await?,
+                    "revoked": false
+                },
+                options,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        let mut res: Vec<ApiKey> = vec![];
+        while let Some(doc) = cur.next().await {
+            res.push(bson::from_document(doc.map_err(AppError::database_error)?).map_err(AppError::decode_error)?);
+        }
+        Ok(res)
+    }
+
+    /// Revokes every key of `user_id`, as signing out everywhere does.
+    pub async fn revoke_user(db: &Database, user_id: &ObjectId) -> Result<(), AppError> {
+        get_coll(db)
+            .update_many(
+                doc! { "user_id": user_id },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+        Ok(())
+    }
+
+    /// Revokes one of `user_id`'s keys, refusing ids that belong to others.
+    pub async fn revoke(db: &Database, user_id: &str, key_id: &str) -> Result<(), AppError> {
+        let res = get_coll(db)
+            .update_one(
+                doc! {
+                    "_id": convert_obj_id(key_id).await?,
+                    "user_id": convert_obj_id(user_id).await?
+                },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        if res.matched_count == 0 {
+            return Err(AppError {
+                cause: None,
+                message: Some("No API Key Found".to_string()),
+                error_type: AppErrorType::NotFoundError,
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+use bson::{doc, oid::ObjectId, DateTime, Document};
+use chrono::{TimeZone, Utc};
+use futures::StreamExt;
+use mongodb::{options::FindOptions, Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{AppError, AppErrorType},
+    middlewares::ClientInfo,
+};
+
+fn get_coll(db: &Database) -> Collection {
+    db.collection("audit_log")
+}
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    LoginSucceeded,
+    LoginFailed,
+    PasswordChanged,
+    PasswordReset,
+    EmailChangeRequested,
+    EmailChanged,
+    RoleChanged,
+    UserSuspended,
+    UserBanned,
+    UserReinstated,
+    AccountDeleted,
+    PostDeleted,
+    CommentDeleted,
+    ReplyDeleted,
+    VotesReconciled,
+    OrphansSwept,
+}
+
+/// One security relevant event. Entries are only ever inserted; nothing in
+/// the application updates or deletes them.
+///
+/// `actor` is who did it, `None` when nobody was signed in, and `target` the
+/// id of the user, post, comment or reply it was done to.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditEvent {
+    #[serde(rename = "_id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub action: AuditAction,
+    pub actor: Option<ObjectId>,
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime,
+}
+
+/// Filters for `GET /admin/audit-log`. `since` and `until` are Unix seconds.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditQuery {
+    pub action: Option<AuditAction>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction, client: &ClientInfo) -> Self {
+        AuditEvent {
+            id: None,
+            action,
+            actor: None,
+            target: None,
+            detail: None,
+            ip: client.ip.clone(),
+            user_agent: client.user_agent.clone(),
+            created_at: DateTime(Utc::now()),
+        }
+    }
+
+    /// Sets the actor from a hex id; ids that do not parse are left out.
+    pub fn actor(mut self, user_id: &str) -> Self {
+        self.actor = ObjectId::with_string(user_id).ok();
+        self
+    }
+
+    pub fn target(mut self, id: &str) -> Self {
+        self.target = Some(id.to_string());
+        self
+    }
+
+    pub fn detail(mut self, detail: &str) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    /// Stores the event. A failed write is logged instead of failing the
+    /// request, since the action it describes has already happened.
+    pub async fn record(self, db: &Database) {
+        if let Err(_e) = get_coll(db)
+            .insert_one(bson::to_document(&self).unwrap(), None)
+            .await
+        {
+            println!("{:?}", _e);
+        }
+    }
+
+    /// Newest events first, narrowed by `query`.
+    pub async fn search(db: &Database, query: &AuditQuery) -> Result<Vec<AuditEvent>, AppError> {
+        let mut filter = Document::new();
+        if let Some(action) = query.action {
+            filter.insert("action", bson::to_bson(&action).unwrap());
+        }
+        if let Some(actor) = &query.actor {
+            let actor = ObjectId::with_string(actor).map_err(|_e| AppError {
+                cause: Some(_e.to_string()),
+                message: Some("Invalid Id".to_string()),
+                error_type: AppErrorType::InvalidId,
+            })?;
+            filter.insert("actor", actor);
+        }
+        if let Some(target) = &query.target {
+            filter.insert("target", target.as_str());
+        }
+        if let Some(ip) = &query.ip {
+            filter.insert("ip", ip.as_str());
+        }
+        let mut created_at = Document::new();
+        for (op, secs) in [("$gte", query.since), ("$lt", query.until)].iter() {
+            if let Some(secs) = secs {
+                let time = Utc.timestamp_opt(*secs, 0).single().ok_or(AppError {
+                    cause: Some("INVALID_TIMESTAMP".to_string()),
+                    message: Some("since and until must be Unix timestamps".to_string()),
+                    error_type: AppErrorType::BadRequest,
+                })?;
+                created_at.insert(*op, time);
+            }
+        }
+        if !created_at.is_empty() {
+            filter.insert("created_at", created_at);
+        }
+
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .limit(query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT))
+            .build();
+        let mut cur = get_coll(db).find(filter, options).await.map_err(AppError::database_error)?;
+
+        let mut res: Vec<AuditEvent> = vec![];
+        while let Some(doc) = cur.next().await {
+            res.push(bson::from_document(doc.map_err(AppError::database_error)?).map_err(AppError::decode_error)?);
+        }
+        Ok(res)
+    }
+}
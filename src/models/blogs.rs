@@ -1,10 +1,22 @@
-use bson::{doc, oid::ObjectId, DateTime};
+use bson::{doc, oid::ObjectId, DateTime, Document};
 use chrono::Utc;
 use futures::StreamExt;
-use mongodb::{options::FindOptions, Collection, Database};
-use serde::{Deserialize, Serialize};
+use mongodb::{options::UpdateOptions, Collection, Database};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::errors::{AppError, AppErrorType};
+use crate::{
+    errors::{AppError, AppErrorType},
+    models::pagination::{find_page, Page, PageQuery, SortOrder},
+};
+
+/// Author name shown on content kept after its author deleted their account.
+pub const DELETED_USERNAME: &str = "[deleted]";
+
+/// Placeholder author id for comments and replies kept after their author
+/// deleted their account. No account can ever have it.
+pub fn deleted_user_id() -> ObjectId {
+    ObjectId::with_bytes([0; 12])
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Votes {
@@ -19,12 +31,341 @@ impl Votes {
             count: 0,
         }
     }
+
+    fn contains(votes: &Option<Votes>, user_id: &ObjectId) -> bool {
+        votes
+            .as_ref()
+            .is_some_and(|votes| votes.users.contains(user_id))
+    }
+
+    /// Every vote `user_id` has cast on posts, comments and replies.
+    pub async fn cast_by(db: &Database, user_id: &ObjectId) -> Result<Vec<CastVote>, AppError> {
+        let mut res = vec![];
+
+        let posts: Vec<BlogPost> = find_all(
+            &get_coll(db),
+            doc! { "$or": [{ "upvotes.users": user_id }, { "downvotes.users": user_id }] },
+        )
+        .await?;
+        for post in posts {
+            for (vote, votes) in [("upvote", &post.upvotes), ("downvote", &post.downvotes)].iter() {
+                if Votes::contains(votes, user_id) {
+                    res.push(CastVote::new("post", post.id.clone().unwrap(), None, vote));
+                }
+            }
+        }
+
+        let comments: Vec<Comments> = find_all(
+            &db.collection("comments"),
+            doc! { "$or": [
+                { "likes.users": user_id },
+                { "dislikes.users": user_id },
+                { "replies.likes.users": user_id },
+                { "replies.dislikes.users": user_id }
+            ] },
+        )
+        .await?;
+        for comment in comments {
+            let comment_id = comment.id.clone().unwrap();
+            for (vote, votes) in [("like", &comment.likes), ("dislike", &comment.dislikes)].iter() {
+                if Votes::contains(votes, user_id) {
+                    res.push(CastVote::new("comment", comment_id.clone(), None, vote));
+                }
+            }
+            for reply in comment.replies.iter().flatten() {
+                for (vote, votes) in [("like", &reply.likes), ("dislike", &reply.dislikes)].iter() {
+                    if Votes::contains(votes, user_id) {
+                        res.push(CastVote::new(
+                            "reply",
+                            reply.id.clone().unwrap(),
+                            Some(comment_id.clone()),
+                            vote,
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Takes `user_id`'s votes off every post, comment and reply.
+    pub async fn remove_user(db: &Database, user_id: &ObjectId) -> Result<(), AppError> {
+        let posts = get_coll(db);
+        let comments = db.collection("comments");
+
+        for field in ["upvotes", "downvotes"].iter() {
+            pull_vote(&posts, field, user_id, false).await?;
+        }
+        for field in ["likes", "dislikes"].iter() {
+            pull_vote(&comments, field, user_id, false).await?;
+            pull_vote(&comments, field, user_id, true).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Pulls `user_id` from `<field>.users` and lowers `<field>.count` on every
+/// document, or with `in_replies` every reply, holding their vote.
+async fn pull_vote(
+    coll: &Collection,
+    field: &str,
+    user_id: &ObjectId,
+    in_replies: bool,
+) -> Result<(), AppError> {
+    let (filter, path, options) = if in_replies {
+        (
+            format!("replies.{}.users", field),
+            format!("replies.$[reply].{}", field),
+            UpdateOptions::builder()
+                .array_filters(vec![doc! { format!("reply.{}.users", field): user_id }])
+                .build(),
+        )
+    } else {
+        (
+            format!("{}.users", field),
+            field.to_string(),
+            UpdateOptions::default(),
+        )
+    };
+
+    coll.update_many(
+        doc! { filter: user_id },
+        doc! {
+            "$pull": { format!("{}.users", path): user_id },
+            "$inc": { format!("{}.count", path): -1 }
+        },
+        options,
+    )
+    .await
+    .map_err(AppError::database_error)?;
+    Ok(())
+}
+
+/// One vote a user has cast, as listed in their data export.
+#[derive(Serialize, Debug)]
+pub struct CastVote {
+    pub target: String,
+    pub id: ObjectId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment_id: Option<ObjectId>,
+    pub vote: String,
+}
+
+impl CastVote {
+    fn new(target: &str, id: ObjectId, comment_id: Option<ObjectId>, vote: &str) -> Self {
+        CastVote {
+            target: target.to_string(),
+            id,
+            comment_id,
+            vote: vote.to_string(),
+        }
+    }
+}
+
+/// Body of the vote endpoints: `1` votes up, `-1` down and `0` withdraws
+/// the caller's vote.
+#[derive(Deserialize, Debug)]
+pub struct VoteRequest {
+    pub value: i32,
 }
 
-#[derive(PartialEq)]
-pub enum IncOrDec {
-    INC,
-    DEC,
+/// A post, comment or reply being voted on. Posts keep their votes in
+/// `upvotes`/`downvotes`, comments and replies in `likes`/`dislikes`.
+pub enum VoteTarget {
+    Post(ObjectId),
+    Comment(ObjectId),
+    Reply {
+        comment_id: ObjectId,
+        reply_id: ObjectId,
+    },
+}
+
+impl VoteTarget {
+    pub async fn post(blog_id: &str) -> Result<Self, AppError> {
+        Ok(VoteTarget::Post(convert_obj_id(blog_id).await?))
+    }
+
+    pub async fn comment(comment_id: &str) -> Result<Self, AppError> {
+        Ok(VoteTarget::Comment(convert_obj_id(comment_id).await?))
+    }
+
+    pub async fn reply(comment_id: &str, reply_id: &str) -> Result<Self, AppError> {
+        Ok(VoteTarget::Reply {
+            comment_id: convert_obj_id(comment_id).await?,
+            reply_id: convert_obj_id(reply_id).await?,
+        })
+    }
+
+    fn coll(&self, db: &Database) -> Collection {
+        match self {
+            VoteTarget::Post(_) => get_coll(db),
+            _ => db.collection("comments"),
+        }
+    }
+
+    /// The up and the down side.
+    fn sides(&self) -> (&'static str, &'static str) {
+        match self {
+            VoteTarget::Post(_) => ("upvotes", "downvotes"),
+            _ => ("likes", "dislikes"),
+        }
+    }
+
+    /// Matches the target only while its vote arrays satisfy `conditions`,
+    /// so every update below is a single compare-and-set on one document.
+    fn filter(&self, conditions: Document) -> Document {
+        match self {
+            VoteTarget::Post(id) | VoteTarget::Comment(id) => {
+                let mut filter = doc! { "_id": id };
+                for (key, value) in conditions {
+                    filter.insert(key, value);
+                }
+                filter
+            }
+            VoteTarget::Reply {
+                comment_id,
+                reply_id,
+            } => {
+                let mut reply = doc! { "_id": reply_id };
+                for (key, value) in conditions {
+                    reply.insert(key, value);
+                }
+                doc! { "_id": comment_id, "replies": { "$elemMatch": reply } }
+            }
+        }
+    }
+
+    fn path(&self, side: &str) -> String {
+        match self {
+            VoteTarget::Reply { .. } => format!("replies.$[reply].{}", side),
+            _ => side.to_string(),
+        }
+    }
+
+    fn options(&self) -> UpdateOptions {
+        match self {
+            VoteTarget::Reply { reply_id, .. } => UpdateOptions::builder()
+                .array_filters(vec![doc! { "reply._id": reply_id }])
+                .build(),
+            _ => UpdateOptions::default(),
+        }
+    }
+
+    async fn update(&self, coll: &Collection, conditions: Document, update: Document) -> Result<i64, AppError> {
+        let res = coll
+            .update_one(self.filter(conditions), update, self.options())
+            .await
+            .map_err(AppError::database_error)?;
+        Ok(res.matched_count)
+    }
+
+    /// Sets `user_id`'s vote to `value`. Each step only applies when the
+    /// arrays still hold the state it expects, so repeating a vote is a
+    /// no-op, counts move together with `users` and nobody ends up on both
+    /// sides, even with concurrent requests.
+    pub async fn vote(&self, db: &Database, user_id: &str, value: i32) -> Result<(), AppError> {
+        let coll = self.coll(db);
+        let user_id = convert_obj_id(user_id).await?;
+        let (up, down) = self.sides();
+        let has = |side: &str| doc! { format!("{}.users", side): &user_id };
+        let lacks = |side: &str| doc! { format!("{}.users", side): { "$ne": &user_id } };
+        let users = |side: &str| format!("{}.users", self.path(side));
+        let count = |side: &str| format!("{}.count", self.path(side));
+
+        let matched = match value {
+            0 => {
+                let mut matched = 0;
+                for &side in [up, down].iter() {
+                    matched += self
+                        .update(
+                            &coll,
+                            has(side),
+                            doc! {
+                                "$pull": { users(side): &user_id },
+                                "$inc": { count(side): -1 }
+                            },
+                        )
+                        .await?;
+                }
+                matched
+            }
+            1 | -1 => {
+                let (side, other) = if value == 1 { (up, down) } else { (down, up) };
+                let mut switch = lacks(side);
+                switch.insert(format!("{}.users", other), &user_id);
+                let switched = self
+                    .update(
+                        &coll,
+                        switch,
+                        doc! {
+                            "$push": { users(side): &user_id },
+                            "$pull": { users(other): &user_id },
+                            "$inc": { count(side): 1, count(other): -1 }
+                        },
+                    )
+                    .await?;
+                if switched > 0 {
+                    switched
+                } else {
+                    let mut fresh = lacks(side);
+                    fresh.insert(format!("{}.users", other), doc! { "$ne": &user_id });
+                    self.update(
+                        &coll,
+                        fresh,
+                        doc! {
+                            "$push": { users(side): &user_id },
+                            "$inc": { count(side): 1 }
+                        },
+                    )
+                    .await?
+                }
+            }
+            _ => {
+                return Err(AppError::invalid_field(
+                    "value",
+                    "INVALID_VOTE",
+                    "Vote with 1, -1 or 0",
+                ))
+            }
+        };
+
+        if matched == 0 {
+            self.ensure_exists(&coll).await?;
+        }
+        Ok(())
+    }
+
+    /// Tells a vote that was already in place apart from a missing target.
+    async fn ensure_exists(&self, coll: &Collection) -> Result<(), AppError> {
+        let found = coll
+            .count_documents(self.filter(Document::new()), None)
+            .await
+            .map_err(AppError::database_error)?;
+        if found > 0 {
+            return Ok(());
+        }
+        let message = match self {
+            VoteTarget::Post(_) => "No Post Found",
+            VoteTarget::Comment(_) => "Comment Not Found",
+            VoteTarget::Reply { .. } => "Reply Not Found",
+        };
+        Err(AppError {
+            cause: None,
+            message: Some(message.to_string()),
+            error_type: AppErrorType::NotFoundError,
+        })
+    }
+}
+
+async fn find_all<T: DeserializeOwned>(coll: &Collection, filter: Document) -> Result<Vec<T>, AppError> {
+    let mut cur = coll.find(filter, None).await.map_err(AppError::database_error)?;
+
+    let mut res: Vec<T> = vec![];
+    while let Some(doc) = cur.next().await {
+        res.push(bson::from_document(doc.map_err(AppError::database_error)?).map_err(AppError::decode_error)?);
+    }
+    Ok(res)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -42,6 +383,9 @@ pub struct BlogPost {
     pub upvotes: Option<Votes>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub downvotes: Option<Votes>,
+    /// Comments on the post, kept in step by saving and deleting comments.
+    #[serde(default)]
+    pub comment_count: i32,
 }
 
 fn get_coll(db: &Database) -> Collection {
@@ -96,93 +440,41 @@ impl BlogPost {
             created_at: DateTime(Utc::now()),
             upvotes: Some(Votes::new()),
             downvotes: Some(Votes::new()),
+            comment_count: 0,
         }
     }
 
-    pub async fn upvote(
-        db: &Database,
-        blog_id: &str,
-        user_id: &str,
-        patch_type: IncOrDec,
-    ) -> Result<(), AppError> {
-        let coll = get_coll(db);
-
-        let blog_id = match ObjectId::with_string(blog_id) {
-            Ok(val) => Ok(val),
-            Err(_e) => Err(AppError {
-                message: None,
-                cause: Some(_e.to_string()),
-                error_type: AppErrorType::InavlidId,
-            }),
-        }?;
-
-        match coll
+    async fn add_comments(db: &Database, blog_id: &ObjectId, delta: i32) -> Result<(), AppError> {
+        get_coll(db)
             .update_one(
-                doc! {
-                    "_id": blog_id
-                },
-                doc! {
-                    if patch_type == IncOrDec::INC {"$push"} else {"$pull"}: {
-                        "upvotes.users": ObjectId::with_string(user_id).unwrap()
-                    },
-                     "$inc": {
-                        "upvotes.count": if patch_type == IncOrDec::DEC {-1} else {1}
-                    }
-                },
+                doc! { "_id": blog_id },
+                doc! { "$inc": { "comment_count": delta } },
                 None,
             )
             .await
-        {
-            Ok(_) => Ok(()),
-            Err(_e) => Err(AppError {
-                cause: Some(_e.to_string()),
-                message: None,
-                error_type: AppErrorType::DatabaseError,
-            }),
-        }
+            .map_err(AppError::database_error)?;
+        Ok(())
     }
 
-    pub async fn downvote(
-        db: &Database,
-        blog_id: &str,
-        user_id: &str,
-        patch_type: IncOrDec,
-    ) -> Result<(), AppError> {
-        let coll = get_coll(db);
-
-        let blog_id = match ObjectId::with_string(blog_id) {
-            Ok(val) => Ok(val),
-            Err(_e) => Err(AppError {
-                message: None,
-                cause: Some(_e.to_string()),
-                error_type: AppErrorType::InavlidId,
-            }),
-        }?;
-
-        match coll
-            .update_one(
-                doc! {
-                    "_id": blog_id
-                },
-                doc! {
-                    if patch_type == IncOrDec::INC {"$push"} else {"$pull"}: {
-                        "downvotes.users": ObjectId::with_string(user_id).unwrap()
-                    },
-                     "$inc": {
-                        "downvotes.count": if patch_type == IncOrDec::DEC {-1} else {1}
-                    }
-                },
-                None,
-            )
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(_e) => Err(AppError {
-                cause: Some(_e.to_string()),
-                message: None,
-                error_type: AppErrorType::DatabaseError,
-            }),
+    /// Sets `comment_count` from the comments actually stored, for bulk
+    /// deletes that span many posts.
+    pub async fn recount_comments(db: &Database, blog_ids: &[ObjectId]) -> Result<(), AppError> {
+        let comments = db.collection("comments");
+        for blog_id in blog_ids {
+            let count = comments
+                .count_documents(doc! { "blog_id": blog_id }, None)
+                .await
+                .map_err(AppError::database_error)?;
+            get_coll(db)
+                .update_one(
+                    doc! { "_id": blog_id },
+                    doc! { "$set": { "comment_count": count as i32 } },
+                    None,
+                )
+                .await
+                .map_err(AppError::database_error)?;
         }
+        Ok(())
     }
 
     pub async fn save(&self, db: &Database) -> Result<(), AppError> {
@@ -200,45 +492,26 @@ impl BlogPost {
         }
     }
 
-    pub async fn get_all_posts(db: &Database) -> Result<Vec<BlogPost>, AppError> {
-        let coll = get_coll(&db);
-        let options = FindOptions::builder()
-            .sort(doc! {"upvotes.count": -1 })
-            .build();
-        let mut cur = match coll.find(doc! {}, options).await {
-            Ok(cur) => Ok(cur),
-            Err(_e) => Err(AppError {
-                cause: Some(_e.to_string()),
-                message: None,
-                error_type: AppErrorType::DatabaseError,
-            }),
-        }?;
-        let mut res: Vec<BlogPost> = vec![];
-        while let Some(val) = cur.next().await {
-            match val {
-                Ok(doc) => {
-                    res.push(bson::from_document::<BlogPost>(doc).unwrap());
-                    Ok(())
-                }
-                Err(_e) => Err(AppError {
-                    cause: Some(_e.to_string()),
-                    message: None,
-                    error_type: AppErrorType::DatabaseError,
-                }),
-            }?;
-        }
-
-        Ok(res)
+    /// Most upvoted first. Votes cast while a client pages through can move
+    /// a post across pages; see `SortOrder`.
+    pub async fn get_all_posts(db: &Database, page: &PageQuery) -> Result<Page<BlogPost>, AppError> {
+        find_page(
+            &get_coll(db),
+            doc! {},
+            SortOrder::by_field("upvotes.count", true),
+            page,
+        )
+        .await
     }
 
     pub async fn get_post_by_id(db: &Database, id: &str) -> Result<BlogPost, AppError> {
         match ObjectId::with_string(id) {
             Ok(id) => {
-                let coll = get_coll(&db);
+                let coll = get_coll(db);
                 match coll
                     .find_one(
                         doc! {
-                            "_id": ObjectId::from(id)
+                            "_id": id
                         },
                         None,
                     )
@@ -252,7 +525,7 @@ impl BlogPost {
                                 error_type: AppErrorType::NotFoundError,
                             });
                         }
-                        let post: BlogPost = bson::from_document(post.unwrap()).unwrap();
+                        let post: BlogPost = bson::from_document(post.unwrap()).map_err(AppError::decode_error)?;
                         Ok(post)
                     }
                     Err(_e) => Err(AppError {
@@ -267,14 +540,14 @@ impl BlogPost {
                 Err(AppError {
                     cause: Some(_e.to_string()),
                     message: Some("Invalid Id".to_string()),
-                    error_type: AppErrorType::InavlidId,
+                    error_type: AppErrorType::InvalidId,
                 })
             }
         }
     }
 
     pub async fn get_posts_by_uid(db: &Database, user_id: &str) -> Result<Vec<BlogPost>, AppError> {
-        let coll = get_coll(&db);
+        let coll = get_coll(db);
 
         let mut cur = match coll.find(doc! {"user_id": user_id}, None).await {
             Ok(any) => Ok(any),
@@ -287,19 +560,38 @@ impl BlogPost {
         let mut res: Vec<BlogPost> = vec![];
 
         while let Some(doc) = cur.next().await {
-            res.push(bson::from_document(doc.unwrap()).unwrap());
+            res.push(bson::from_document(doc.map_err(AppError::database_error)?).map_err(AppError::decode_error)?);
         }
 
         Ok(res)
     }
 
-    pub async fn delete_blog(db: &Database, blog_id: &str, user_id: &str) -> Result<(), AppError> {
+    /// Newest first.
+    pub async fn get_posts_page_by_uid(
+        db: &Database,
+        user_id: &str,
+        page: &PageQuery,
+    ) -> Result<Page<BlogPost>, AppError> {
+        find_page(
+            &get_coll(db),
+            doc! {"user_id": user_id},
+            SortOrder::by_id(true),
+            page,
+        )
+        .await
+    }
+
+    /// Deletes the post, then its comments with their replies. The driver
+    /// has no transactions, so the post goes first: if removing the comments
+    /// fails they are left orphaned, never reachable, and `OrphanReport`
+    /// picks them up, rather than a live post losing its thread.
+    pub async fn delete_blog(db: &Database, blog_id: &str) -> Result<(), AppError> {
         let blog_id = match ObjectId::with_string(blog_id) {
             Ok(val) => Ok(val),
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
                 message: None,
-                error_type: AppErrorType::InavlidId,
+                error_type: AppErrorType::InvalidId,
             }),
         }?;
 
@@ -307,8 +599,7 @@ impl BlogPost {
         match coll
             .delete_one(
                 doc! {
-                    "_id": blog_id,
-                    "user_id": user_id
+                    "_id": &blog_id
                 },
                 None,
             )
@@ -320,10 +611,64 @@ impl BlogPost {
                 message: None,
                 error_type: AppErrorType::DatabaseError,
             }),
-        }
+        }?;
+
+        db.collection("comments")
+            .delete_many(doc! { "blog_id": &blog_id }, None)
+            .await
+            .map_err(AppError::database_error)?;
+        Ok(())
+    }
+
+    /// Deletes every post by `user_id` together with the comments on them.
+    pub async fn delete_by_user(db: &Database, user_id: &ObjectId) -> Result<(), AppError> {
+        let coll = get_coll(db);
+        let posts: Vec<BlogPost> = find_all(&coll, doc! { "user_id": user_id.to_hex() }).await?;
+        let blog_ids: Vec<ObjectId> = posts.into_iter().filter_map(|post| post.id).collect();
+
+        coll.delete_many(doc! { "_id": { "$in": &blog_ids } }, None)
+            .await
+            .map_err(AppError::database_error)?;
+        db.collection("comments")
+            .delete_many(doc! { "blog_id": { "$in": &blog_ids } }, None)
+            .await
+            .map_err(AppError::database_error)?;
+        Ok(())
+    }
+
+    /// Keeps `user_id`'s posts but detaches them from the account.
+    pub async fn anonymize_user(db: &Database, user_id: &ObjectId) -> Result<(), AppError> {
+        get_coll(db)
+            .update_many(
+                doc! { "user_id": user_id.to_hex() },
+                doc! {
+                    "$unset": { "user_id": "" },
+                    "$set": { "username": DELETED_USERNAME }
+                },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+        Ok(())
     }
 }
 
+/// Order of `GET /blog/{id}/comments`. `top` is by likes.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentSort {
+    Newest,
+    #[default]
+    Oldest,
+    Top,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CommentListQuery {
+    #[serde(default)]
+    pub sort: CommentSort,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Comments {
     #[serde(rename = "_id")]
@@ -390,88 +735,10 @@ impl Replies {
             dislikes: Some(Votes::new()),
         })
     }
-
-    pub async fn likes(
-        db: &Database,
-        user_id: &str,
-        comment_id: &str,
-        reply_id: &str,
-        patch_type: IncOrDec,
-    ) -> Result<(), AppError> {
-        let coll = db.collection("comments");
-
-        match coll
-            .update_one(
-                doc! {
-                    "_id": convert_obj_id(comment_id).await?,
-                    "replies._id":
-                        convert_obj_id(reply_id).await?,
-
-                },
-                doc! {
-                    if patch_type == IncOrDec::INC {"$push"} else {"$pull"}: {
-                        "replies.$.likes.users": convert_obj_id(user_id).await?
-                    },
-                     "$inc": {
-                        "replies.$.likes.count": if patch_type == IncOrDec::DEC {-1} else {1}
-                    }
-                },
-                None,
-            )
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(_e) => Err(AppError {
-                cause: Some(_e.to_string()),
-                message: None,
-                error_type: AppErrorType::DatabaseError,
-            }),
-        }
-    }
-
-    pub async fn dislikes(
-        db: &Database,
-        user_id: &str,
-        comment_id: &str,
-        reply_id: &str,
-        patch_type: IncOrDec,
-    ) -> Result<(), AppError> {
-        let coll = db.collection("comments");
-
-        match coll
-            .update_one(
-                doc! {
-                    "_id": convert_obj_id(comment_id).await?,
-                    "replies._id":
-                        convert_obj_id(reply_id).await?,
-
-                },
-                doc! {
-                    if patch_type == IncOrDec::INC {"$push"} else {"$pull"}: {
-                        "replies.$.dislikes.users": convert_obj_id(user_id).await?
-                    },
-                     "$inc": {
-                        "replies.$.dislikes.count": if patch_type == IncOrDec::DEC {-1} else {1}
-                    }
-                },
-                None,
-            )
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(_e) => Err(AppError {
-                cause: Some(_e.to_string()),
-                message: None,
-                error_type: AppErrorType::DatabaseError,
-            }),
-        }
-    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct PostReply {
-    pub user_id: String,
-    pub username: String,
     pub content: String,
 }
 
@@ -514,8 +781,6 @@ impl PostReply {
 
 #[derive(Deserialize, Debug)]
 pub struct PostComment {
-    pub user_id: String,
-    pub username: String,
     pub content: String,
     pub blog_id: String,
 }
@@ -526,24 +791,42 @@ async fn convert_obj_id(id: &str) -> Result<ObjectId, AppError> {
         Err(_e) => Err(AppError {
             cause: Some(_e.to_string()),
             message: None,
-            error_type: AppErrorType::InavlidId,
+            error_type: AppErrorType::InvalidId,
         }),
     }
 }
 
 impl PostComment {
-    pub async fn save(&self, db: &Database) -> Result<String, AppError> {
+    pub async fn save(
+        &self,
+        db: &Database,
+        user_id: &str,
+        username: &str,
+    ) -> Result<String, AppError> {
         let coll = db.collection("comments");
 
         let comment = Comments::new(
-            &self.user_id.as_str(),
-            &self.username.as_str(),
-            &self.content.as_str(),
-            &self.blog_id.as_str(),
+            user_id,
+            username,
+            self.content.as_str(),
+            self.blog_id.as_str(),
         )
         .await?;
+        // A post deleted after this check leaves the comment to the orphan
+        // sweep.
+        let post = get_coll(db)
+            .find_one(doc! { "_id": &comment.blog_id }, None)
+            .await
+            .map_err(AppError::database_error)?;
+        if post.is_none() {
+            return Err(AppError {
+                cause: None,
+                message: Some("No Post Found".to_string()),
+                error_type: AppErrorType::NotFoundError,
+            });
+        }
 
-        match coll
+        let id = match coll
             .insert_one(bson::to_document(&comment).unwrap(), None)
             .await
         {
@@ -553,7 +836,9 @@ impl PostComment {
                 message: None,
                 error_type: AppErrorType::DatabaseError,
             }),
-        }
+        }?;
+        BlogPost::add_comments(db, &comment.blog_id, 1).await?;
+        Ok(id)
     }
 
     pub async fn patch_comments(&self, db: &Database, comment_id: &str) -> Result<(), AppError> {
@@ -586,106 +871,26 @@ impl PostComment {
 }
 
 impl Comments {
-    pub async fn like(
-        db: &Database,
-        comment_id: &str,
-        user_id: &str,
-        patch_type: IncOrDec,
-    ) -> Result<(), AppError> {
-        let coll = db.collection("comments");
-        match coll
-            .update_one(
-                doc! {"_id": convert_obj_id(comment_id).await?},
-                doc! {
-                    if patch_type == IncOrDec::INC {"$push"} else {"$pull"}: {
-                        "likes.users": ObjectId::with_string(user_id).unwrap()
-                    },
-                     "$inc": {
-                        "likes.count": if patch_type == IncOrDec::DEC {-1} else {1}
-                    }
-                },
-                None,
-            )
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(_e) => Err(AppError {
-                cause: Some(_e.to_string()),
-                message: None,
-                error_type: AppErrorType::DatabaseError,
-            }),
-        }
-    }
-
-    pub async fn dislike(
-        db: &Database,
-        comment_id: &str,
-        user_id: &str,
-        patch_type: IncOrDec,
-    ) -> Result<(), AppError> {
-        let coll = db.collection("comments");
-        match coll
-            .update_one(
-                doc! {"_id": convert_obj_id(comment_id).await?},
-                doc! {
-                    if patch_type == IncOrDec::INC {"$push"} else {"$pull"}: {
-                        "dislikes.users": ObjectId::with_string(user_id).unwrap()
-                    },
-                     "$inc": {
-                        "dislikes.count": if patch_type == IncOrDec::DEC {-1} else {1}
-                    }
-                },
-                None,
-            )
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(_e) => Err(AppError {
-                cause: Some(_e.to_string()),
-                message: None,
-                error_type: AppErrorType::DatabaseError,
-            }),
-        }
-    }
-
     pub async fn get_comments_by_post(
         db: &Database,
         blog_id: &str,
-    ) -> Result<Vec<Comments>, AppError> {
-        let coll = db.collection("comments");
-        let mut cur = match coll
-            .find(
-                doc! {
-                    "blog_id": ObjectId::with_string(blog_id).unwrap()
-                },
-                None,
-            )
-            .await
-        {
-            Ok(val) => Ok(val),
-            Err(_e) => Err(AppError {
-                cause: Some(_e.to_string()),
-                message: None,
-                error_type: AppErrorType::DatabaseError,
-            }),
-        }?;
-
-        let mut res: Vec<Comments> = vec![];
-
-        while let Some(val) = cur.next().await {
-            match val {
-                Ok(doc) => {
-                    res.push(bson::from_document::<Comments>(doc).unwrap());
-                    Ok(())
-                }
-                Err(_e) => Err(AppError {
-                    cause: Some(_e.to_string()),
-                    message: None,
-                    error_type: AppErrorType::DatabaseError,
-                }),
-            }?;
-        }
-        Ok(res)
+        sort: CommentSort,
+        page: &PageQuery,
+    ) -> Result<Page<Comments>, AppError> {
+        let order = match sort {
+            CommentSort::Newest => SortOrder::by_id(true),
+            CommentSort::Oldest => SortOrder::by_id(false),
+            CommentSort::Top => SortOrder::by_field("likes.count", true),
+        };
+        find_page(
+            &db.collection("comments"),
+            doc! {
+                "blog_id": convert_obj_id(blog_id).await?
+            },
+            order,
+            page,
+        )
+        .await
     }
 
     pub async fn get_comments_by_id(db: &Database, comment_id: &str) -> Result<Comments, AppError> {
@@ -695,7 +900,7 @@ impl Comments {
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
                 message: None,
-                error_type: AppErrorType::InavlidId,
+                error_type: AppErrorType::InvalidId,
             }),
         }?;
         let res = match coll.find_one(doc! {"_id": comment_id }, None).await {
@@ -703,7 +908,7 @@ impl Comments {
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
                 message: None,
-                error_type: AppErrorType::InavlidId,
+                error_type: AppErrorType::DatabaseError,
             }),
         }?;
 
@@ -715,18 +920,40 @@ impl Comments {
             });
         }
 
-        Ok(bson::from_document::<Comments>(res.unwrap()).unwrap())
+        bson::from_document::<Comments>(res.unwrap()).map_err(AppError::decode_error)
+    }
+
+    pub fn get_reply(&self, reply_id: &str) -> Result<&Replies, AppError> {
+        let reply_id = match ObjectId::with_string(reply_id) {
+            Ok(val) => Ok(val),
+            Err(_e) => Err(AppError {
+                cause: Some(_e.to_string()),
+                message: None,
+                error_type: AppErrorType::InvalidId,
+            }),
+        }?;
+
+        self.replies
+            .iter()
+            .flatten()
+            .find(|reply| reply.id.as_ref() == Some(&reply_id))
+            .ok_or(AppError {
+                cause: None,
+                message: Some("Reply Not Found".to_string()),
+                error_type: AppErrorType::NotFoundError,
+            })
     }
 
-    pub async fn save_reply(&self, db: &Database, reply: PostReply) -> Result<String, AppError> {
+    pub async fn save_reply(
+        &self,
+        db: &Database,
+        reply: PostReply,
+        user_id: &str,
+        username: &str,
+    ) -> Result<String, AppError> {
         let coll = db.collection("comments");
 
-        let reply = Replies::new(
-            reply.user_id.as_str(),
-            reply.username.as_str(),
-            reply.content.as_str(),
-        )
-        .await?;
+        let reply = Replies::new(user_id, username, reply.content.as_str()).await?;
 
         match coll
             .update_one(
@@ -756,8 +983,8 @@ impl Comments {
     pub async fn delete(db: &Database, comment_id: &str) -> Result<(), AppError> {
         let coll = db.collection("comments");
         let comment_id = convert_obj_id(comment_id).await?;
-        match coll
-            .delete_one(
+        let deleted = match coll
+            .find_one_and_delete(
                 doc! {
                     "_id": comment_id
                 },
@@ -765,13 +992,19 @@ impl Comments {
             )
             .await
         {
-            Ok(_) => Ok(()),
+            Ok(doc) => Ok(doc),
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
                 message: None,
                 error_type: AppErrorType::DatabaseError,
             }),
+        }?;
+
+        if let Some(doc) = deleted {
+            let comment: Comments = bson::from_document(doc).map_err(AppError::decode_error)?;
+            BlogPost::add_comments(db, &comment.blog_id, -1).await?;
         }
+        Ok(())
     }
 
     pub async fn delete_reply(
@@ -809,4 +1042,70 @@ impl Comments {
             }),
         }
     }
+
+    pub async fn get_by_user(db: &Database, user_id: &ObjectId) -> Result<Vec<Comments>, AppError> {
+        find_all(&db.collection("comments"), doc! { "user_id": user_id }).await
+    }
+
+    /// Comments holding at least one reply by `user_id`.
+    pub async fn get_replied_to_by(
+        db: &Database,
+        user_id: &ObjectId,
+    ) -> Result<Vec<Comments>, AppError> {
+        find_all(&db.collection("comments"), doc! { "replies.user_id": user_id }).await
+    }
+
+    /// Deletes every comment and reply by `user_id`.
+    pub async fn delete_by_user(db: &Database, user_id: &ObjectId) -> Result<(), AppError> {
+        let coll = db.collection("comments");
+
+        let comments = Comments::get_by_user(db, user_id).await?;
+        coll.delete_many(doc! { "user_id": user_id }, None)
+            .await
+            .map_err(AppError::database_error)?;
+        let mut blog_ids: Vec<ObjectId> = comments.into_iter().map(|c| c.blog_id).collect();
+        blog_ids.sort_by_key(|id| id.to_hex());
+        blog_ids.dedup();
+        BlogPost::recount_comments(db, &blog_ids).await?;
+        coll.update_many(
+            doc! { "replies.user_id": user_id },
+            doc! { "$pull": { "replies": { "user_id": user_id } } },
+            None,
+        )
+        .await
+        .map_err(AppError::database_error)?;
+        Ok(())
+    }
+
+    /// Keeps `user_id`'s comments and replies under a placeholder author.
+    pub async fn anonymize_user(db: &Database, user_id: &ObjectId) -> Result<(), AppError> {
+        let coll = db.collection("comments");
+
+        coll.update_many(
+            doc! { "user_id": user_id },
+            doc! {
+                "$set": { "user_id": deleted_user_id(), "username": DELETED_USERNAME }
+            },
+            None,
+        )
+        .await
+        .map_err(AppError::database_error)?;
+
+        let options = UpdateOptions::builder()
+            .array_filters(vec![doc! { "reply.user_id": user_id }])
+            .build();
+        coll.update_many(
+            doc! { "replies.user_id": user_id },
+            doc! {
+                "$set": {
+                    "replies.$[reply].user_id": deleted_user_id(),
+                    "replies.$[reply].username": DELETED_USERNAME
+                }
+            },
+            options,
+        )
+        .await
+        .map_err(AppError::database_error)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,98 @@
+use actix_web::web;
+use bson::oid::ObjectId;
+use mongodb::Database;
+use serde::Serialize;
+use std::io::{Cursor, Write};
+use zip::{write::FileOptions, ZipWriter};
+
+use crate::{
+    errors::{AppError, AppErrorType},
+    models::{
+        blogs::{BlogPost, CastVote, Comments, Replies, Votes},
+        user::{User, UserDetails},
+    },
+};
+
+/// A reply by the exporting user with the comment and post it belongs to.
+#[derive(Serialize, Debug)]
+pub struct ExportedReply {
+    pub comment_id: ObjectId,
+    pub blog_id: ObjectId,
+    pub reply: Replies,
+}
+
+/// Everything stored about one user, as handed out by `GET /user/export`.
+#[derive(Serialize, Debug)]
+pub struct AccountExport {
+    pub profile: UserDetails,
+    pub posts: Vec<BlogPost>,
+    pub comments: Vec<Comments>,
+    pub replies: Vec<ExportedReply>,
+    pub votes: Vec<CastVote>,
+}
+
+impl AccountExport {
+    pub async fn collect(db: &Database, user_id: &str) -> Result<AccountExport, AppError> {
+        let profile = User::get_user_by_id(db, user_id).await?;
+        let id = profile.id.clone().unwrap();
+
+        let mut replies = vec![];
+        for comment in Comments::get_replied_to_by(db, &id).await? {
+            let comment_id = comment.id.clone().unwrap();
+            for reply in comment.replies.into_iter().flatten() {
+                if reply.user_id == id {
+                    replies.push(ExportedReply {
+                        comment_id: comment_id.clone(),
+                        blog_id: comment.blog_id.clone(),
+                        reply,
+                    });
+                }
+            }
+        }
+
+        Ok(AccountExport {
+            posts: BlogPost::get_posts_by_uid(db, user_id).await?,
+            comments: Comments::get_by_user(db, &id).await?,
+            replies,
+            votes: Votes::cast_by(db, &id).await?,
+            profile,
+        })
+    }
+
+    /// Zips each section as its own JSON file, plus the avatar as
+    /// `avatar/<name>` when one was uploaded. Compression runs on the
+    /// blocking pool.
+    pub async fn into_zip(self, avatar: Option<(String, Vec<u8>)>) -> Result<Vec<u8>, AppError> {
+        web::block(move || -> Result<Vec<u8>, String> {
+            let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+            let options = FileOptions::default();
+
+            let sections = vec![
+                ("profile.json", serde_json::to_vec_pretty(&self.profile)),
+                ("posts.json", serde_json::to_vec_pretty(&self.posts)),
+                ("comments.json", serde_json::to_vec_pretty(&self.comments)),
+                ("replies.json", serde_json::to_vec_pretty(&self.replies)),
+                ("votes.json", serde_json::to_vec_pretty(&self.votes)),
+            ];
+            for (name, content) in sections {
+                let content = content.map_err(|err| err.to_string())?;
+                zip.start_file(name, options).map_err(|err| err.to_string())?;
+                zip.write_all(&content).map_err(|err| err.to_string())?;
+            }
+            if let Some((name, content)) = avatar {
+                zip.start_file(format!("avatar/{}", name), options)
+                    .map_err(|err| err.to_string())?;
+                zip.write_all(&content).map_err(|err| err.to_string())?;
+            }
+
+            let archive = zip.finish().map_err(|err| err.to_string())?;
+            Ok(archive.into_inner())
+        })
+        .await
+        .map_err(|err| AppError {
+            cause: Some(err.to_string()),
+            message: Some("Could not build the export".to_string()),
+            error_type: AppErrorType::FileUploadError,
+        })
+    }
+}
@@ -0,0 +1,222 @@
+use bson::{doc, oid::ObjectId, DateTime};
+use chrono::{Duration, Utc};
+use mongodb::{
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{AppError, AppErrorType};
+
+/// Failures tolerated per account before it is locked.
+const ACCOUNT_FREE_ATTEMPTS: i32 = 5;
+/// Failures tolerated per client address, which may front many accounts.
+const IP_FREE_ATTEMPTS: i32 = 20;
+/// First lock duration; it doubles with every further failure.
+const BASE_LOCK_SECONDS: i64 = 30;
+const MAX_LOCK_SECONDS: i64 = 3600;
+/// Failures older than this no longer count towards a lock.
+const FAILURE_WINDOW_HOURS: i64 = 24;
+
+fn get_coll(db: &Database) -> Collection {
+    db.collection("login_attempts")
+}
+
+fn get_lockout_coll(db: &Database) -> Collection {
+    db.collection("lockouts")
+}
+
+/// Failed-attempt counter for one account (`account:<id>`) or client address
+/// (`ip:<addr>`).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoginAttempt {
+    #[serde(rename = "_id")]
+    pub key: String,
+    pub failures: i32,
+    pub last_failure: DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locked_until: Option<DateTime>,
+}
+
+/// Record of a lock being applied, kept for review.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Lockout {
+    #[serde(rename = "_id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub key: String,
+    pub user_id: Option<ObjectId>,
+    pub ip: Option<String>,
+    pub failures: i32,
+    pub locked_until: DateTime,
+    pub created_at: DateTime,
+}
+
+/// Tracks failed credential checks for an account and the address they came
+/// from, backing off exponentially once either passes its allowance.
+pub struct LoginGuard {
+    user_id: Option<ObjectId>,
+    ip: Option<String>,
+}
+
+impl LoginGuard {
+    pub fn new(user_id: Option<&ObjectId>, ip: Option<&str>) -> Self {
+        LoginGuard {
+            user_id: user_id.cloned(),
+            ip: ip.map(|ip| ip.to_string()),
+        }
+    }
+
+    fn keys(&self) -> Vec<(String, i32)> {
+        let mut keys = vec![];
+        if let Some(user_id) = &self.user_id {
+            keys.push((format!("account:{}", user_id), ACCOUNT_FREE_ATTEMPTS));
+        }
+        if let Some(ip) = &self.ip {
+            keys.push((format!("ip:{}", ip), IP_FREE_ATTEMPTS));
+        }
+        keys
+    }
+
+    /// Fails with `TooManyRequests` while the account or address is locked.
+    pub async fn check(&self, db: &Database) -> Result<(), AppError> {
+        let keys: Vec<String> = self.keys().into_iter().map(|(key, _)| key).collect();
+        let locked = get_coll(db)
+            .find_one(
+                doc! {
+                    "_id": { "$in": keys },
+                    "locked_until": { "$gt": Utc::now() }
+                },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        match locked {
+            Some(doc) => {
+                let attempt: LoginAttempt = bson::from_document(doc).map_err(AppError::decode_error)?;
+                let seconds = attempt
+                    .locked_until
+                    .map(|until| (until.0 - Utc::now()).num_seconds())
+                    .unwrap_or_default();
+                Err(AppError {
+                    cause: Some("TOO_MANY_ATTEMPTS".to_string()),
+                    message: Some(format!(
+                        "Too many failed attempts, try again in {} seconds",
+                        seconds.max(1)
+                    )),
+                    error_type: AppErrorType::TooManyRequests,
+                })
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a failure against the account and address. Returns the lock
+    /// expiry when this failure is the one that first locked the account, so
+    /// the caller can tell its owner.
+    pub async fn failed(&self, db: &Database) -> Result<Option<DateTime>, AppError> {
+        let mut account_locked = None;
+
+        for (key, free_attempts) in self.keys() {
+            let failures = increment(db, key.as_str()).await?;
+            if failures < free_attempts {
+                continue;
+            }
+
+            let seconds = BASE_LOCK_SECONDS
+                .saturating_mul(1 << (failures - free_attempts).min(16))
+                .min(MAX_LOCK_SECONDS);
+            let locked_until = DateTime(Utc::now() + Duration::seconds(seconds));
+            lock(db, self, key.as_str(), failures, locked_until).await?;
+
+            if key.starts_with("account:") && failures == free_attempts {
+                account_locked = Some(locked_until);
+            }
+        }
+        Ok(account_locked)
+    }
+
+    /// Clears the account's failures after a successful login. The address
+    /// keeps its count, so spraying many accounts still backs off.
+    pub async fn succeeded(&self, db: &Database) -> Result<(), AppError> {
+        if let Some(user_id) = &self.user_id {
+            get_coll(db)
+                .delete_one(doc! { "_id": format!("account:{}", user_id) }, None)
+                .await
+                .map_err(AppError::database_error)?;
+        }
+        Ok(())
+    }
+}
+
+async fn increment(db: &Database, key: &str) -> Result<i32, AppError> {
+    let coll = get_coll(db);
+
+    coll.update_one(
+        doc! {
+            "_id": key,
+            "last_failure": { "$lt": Utc::now() - Duration::hours(FAILURE_WINDOW_HOURS) }
+        },
+        doc! { "$set": { "failures": 0 } },
+        None,
+    )
+    .await
+    .map_err(AppError::database_error)?;
+
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    let doc = coll
+        .find_one_and_update(
+            doc! { "_id": key },
+            doc! {
+                "$inc": { "failures": 1 },
+                "$set": { "last_failure": Utc::now() }
+            },
+            options,
+        )
+        .await
+        .map_err(AppError::database_error)?
+        .ok_or(AppError {
+            cause: Some("UPSERT_RETURNED_NOTHING".to_string()),
+            message: None,
+            error_type: AppErrorType::DatabaseError,
+        })?;
+
+    let attempt: LoginAttempt = bson::from_document(doc).map_err(AppError::decode_error)?;
+    Ok(attempt.failures)
+}
+
+async fn lock(
+    db: &Database,
+    guard: &LoginGuard,
+    key: &str,
+    failures: i32,
+    locked_until: DateTime,
+) -> Result<(), AppError> {
+    get_coll(db)
+        .update_one(
+            doc! { "_id": key },
+            doc! { "$set": { "locked_until": locked_until.0 } },
+            None,
+        )
+        .await
+        .map_err(AppError::database_error)?;
+
+    let lockout = Lockout {
+        id: None,
+        key: key.to_string(),
+        user_id: guard.user_id.clone(),
+        ip: guard.ip.clone(),
+        failures,
+        locked_until,
+        created_at: DateTime(Utc::now()),
+    };
+    get_lockout_coll(db)
+        .insert_one(bson::to_document(&lockout).unwrap(), None)
+        .await
+        .map_err(AppError::database_error)?;
+    Ok(())
+}
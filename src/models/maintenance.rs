@@ -0,0 +1,376 @@
+use bson::{doc, oid::ObjectId, Bson, Document};
+use chrono::{DateTime, Duration, Utc};
+use futures::StreamExt;
+use mongodb::{options::UpdateOptions, Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::s3_aws,
+    errors::AppError,
+    models::blogs::{deleted_user_id, BlogPost, Comments, Votes},
+};
+
+/// Uploads younger than this may belong to a sign-up still in progress.
+const AVATAR_GRACE_SECS: i64 = 3600;
+
+/// Query of the maintenance endpoints. Without `apply` they only report.
+#[derive(Deserialize, Debug)]
+pub struct MaintenanceQuery {
+    #[serde(default)]
+    pub apply: bool,
+}
+
+/// One vote field whose `count` or `users` is off.
+#[derive(Serialize, Debug)]
+pub struct VoteDiscrepancy {
+    pub target: String,
+    pub id: ObjectId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment_id: Option<ObjectId>,
+    pub field: String,
+    pub stored_count: i32,
+    pub actual_count: i32,
+    pub duplicate_users: usize,
+    /// Whether the fix was written. Stays false in a dry run and when the
+    /// field changed between reading and writing it.
+    pub fixed: bool,
+}
+
+/// A post whose `comment_count` does not match its comments, including
+/// posts written before the field existed.
+#[derive(Serialize, Debug)]
+pub struct CommentCountDiscrepancy {
+    pub id: ObjectId,
+    pub stored_count: i32,
+    pub actual_count: i32,
+    /// Same meaning as on `VoteDiscrepancy`.
+    pub fixed: bool,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct VoteReport {
+    pub applied: bool,
+    pub posts_scanned: u64,
+    pub comments_scanned: u64,
+    pub discrepancies: Vec<VoteDiscrepancy>,
+    pub comment_counts: Vec<CommentCountDiscrepancy>,
+}
+
+/// `users` without repeats, first occurrence kept.
+fn dedup(users: &[ObjectId]) -> Vec<ObjectId> {
+    let mut unique: Vec<ObjectId> = Vec::with_capacity(users.len());
+    for user in users {
+        if !unique.contains(user) {
+            unique.push(user.clone());
+        }
+    }
+    unique
+}
+
+impl VoteReport {
+    /// Recomputes every vote `count` on posts, comments and replies from its
+    /// deduplicated `users`, and every post's `comment_count` from its
+    /// comments. With `apply`, writes the corrections.
+    pub async fn reconcile(db: &Database, apply: bool) -> Result<VoteReport, AppError> {
+        let mut report = VoteReport {
+            applied: apply,
+            ..VoteReport::default()
+        };
+
+        let posts = db.collection("blog_posts");
+        let mut cur = posts.find(None, None).await.map_err(AppError::database_error)?;
+        while let Some(doc) = cur.next().await {
+            let post: BlogPost = bson::from_document(doc.map_err(AppError::database_error)?).map_err(AppError::decode_error)?;
+            let id = match post.id.clone() {
+                Some(id) => id,
+                None => continue,
+            };
+            report.posts_scanned += 1;
+            for (field, votes) in [("upvotes", &post.upvotes), ("downvotes", &post.downvotes)].iter() {
+                report
+                    .check(&posts, "post", &id, None, field, votes)
+                    .await?;
+            }
+            report.check_comment_count(db, &id, post.comment_count).await?;
+        }
+
+        let comments = db.collection("comments");
+        let mut cur = comments.find(None, None).await.map_err(AppError::database_error)?;
+        while let Some(doc) = cur.next().await {
+            let comment: Comments = bson::from_document(doc.map_err(AppError::database_error)?).map_err(AppError::decode_error)?;
+            let id = match comment.id.clone() {
+                Some(id) => id,
+                None => continue,
+            };
+            report.comments_scanned += 1;
+            for (field, votes) in [("likes", &comment.likes), ("dislikes", &comment.dislikes)].iter() {
+                report
+                    .check(&comments, "comment", &id, None, field, votes)
+                    .await?;
+            }
+            for reply in comment.replies.iter().flatten() {
+                // Replies saved without an id cannot be addressed to fix.
+                let reply_id = match reply.id.clone() {
+                    Some(id) => id,
+                    None => continue,
+                };
+                for (field, votes) in [("likes", &reply.likes), ("dislikes", &reply.dislikes)].iter() {
+                    report
+                        .check(&comments, "reply", &reply_id, Some(&id), field, votes)
+                        .await?;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Like `check` for a post's `comment_count`. The write only lands if
+    /// the count is still what was read, a missing field reading as 0.
+    async fn check_comment_count(
+        &mut self,
+        db: &Database,
+        id: &ObjectId,
+        stored_count: i32,
+    ) -> Result<(), AppError> {
+        let actual_count = db
+            .collection("comments")
+            .count_documents(doc! { "blog_id": id }, None)
+            .await
+            .map_err(AppError::database_error)? as i32;
+        if stored_count == actual_count {
+            return Ok(());
+        }
+
+        let mut fixed = false;
+        if self.applied {
+            let unchanged = if stored_count == 0 {
+                doc! { "$in": [0, Bson::Null] }
+            } else {
+                doc! { "$eq": stored_count }
+            };
+            let res = db
+                .collection("blog_posts")
+                .update_one(
+                    doc! { "_id": id, "comment_count": unchanged },
+                    doc! { "$set": { "comment_count": actual_count } },
+                    None,
+                )
+                .await
+                .map_err(AppError::database_error)?;
+            fixed = res.modified_count > 0;
+        }
+
+        self.comment_counts.push(CommentCountDiscrepancy {
+            id: id.clone(),
+            stored_count,
+            actual_count,
+            fixed,
+        });
+        Ok(())
+    }
+
+    /// Compares one field and, when applying, rewrites it only if `users`
+    /// still holds exactly what was read, so votes cast meanwhile are kept.
+    async fn check(
+        &mut self,
+        coll: &Collection,
+        target: &str,
+        id: &ObjectId,
+        comment_id: Option<&ObjectId>,
+        field: &str,
+        votes: &Option<Votes>,
+    ) -> Result<(), AppError> {
+        let votes = match votes {
+            Some(votes) => votes,
+            None => return Ok(()),
+        };
+        let unique = dedup(&votes.users);
+        let actual_count = unique.len() as i32;
+        if votes.count == actual_count && unique.len() == votes.users.len() {
+            return Ok(());
+        }
+
+        let mut fixed = false;
+        if self.applied {
+            let (filter, path, options) = match comment_id {
+                Some(comment_id) => (
+                    doc! {
+                        "_id": comment_id,
+                        "replies": { "$elemMatch": { "_id": id, format!("{}.users", field): &votes.users } }
+                    },
+                    format!("replies.$[reply].{}", field),
+                    UpdateOptions::builder()
+                        .array_filters(vec![doc! { "reply._id": id }])
+                        .build(),
+                ),
+                None => (
+                    doc! { "_id": id, format!("{}.users", field): &votes.users },
+                    field.to_string(),
+                    UpdateOptions::default(),
+                ),
+            };
+            let update: Document = doc! {
+                "$set": {
+                    format!("{}.users", path): &unique,
+                    format!("{}.count", path): actual_count
+                }
+            };
+            let res = coll
+                .update_one(filter, update, options)
+                .await
+                .map_err(AppError::database_error)?;
+            fixed = res.modified_count > 0;
+        }
+
+        self.discrepancies.push(VoteDiscrepancy {
+            target: target.to_string(),
+            id: id.clone(),
+            comment_id: comment_id.cloned(),
+            field: field.to_string(),
+            stored_count: votes.count,
+            actual_count,
+            duplicate_users: votes.users.len() - unique.len(),
+            fixed,
+        });
+        Ok(())
+    }
+}
+
+/// Leftovers of deletes that stopped halfway or ran before deletes cascaded.
+#[derive(Serialize, Debug, Default)]
+pub struct OrphanReport {
+    pub applied: bool,
+    /// Posts that comments still point at but which no longer exist.
+    pub missing_posts: Vec<ObjectId>,
+    /// Comments on those posts; removed when applying.
+    pub orphan_comments: i64,
+    /// Accounts still referenced as author or voter. Applying treats them
+    /// like an anonymizing account deletion: their votes are taken back and
+    /// their posts, comments and replies detached.
+    pub missing_users: Vec<ObjectId>,
+    /// Paths under the avatar prefix that no account uses.
+    pub stale_avatars: Vec<String>,
+}
+
+fn object_ids(values: Vec<Bson>) -> Vec<ObjectId> {
+    values
+        .into_iter()
+        .filter_map(|value| match value {
+            Bson::ObjectId(id) => Some(id),
+            Bson::String(id) => ObjectId::with_string(id.as_str()).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The `candidates` that have no document in `coll`.
+async fn missing(coll: &Collection, candidates: Vec<ObjectId>) -> Result<Vec<ObjectId>, AppError> {
+    let existing = object_ids(
+        coll.distinct("_id", doc! { "_id": { "$in": &candidates } }, None)
+            .await
+            .map_err(AppError::database_error)?,
+    );
+    Ok(candidates
+        .into_iter()
+        .filter(|id| !existing.contains(id))
+        .collect())
+}
+
+impl OrphanReport {
+    /// Finds orphans and, with `apply`, cleans them up. Every step only
+    /// touches what is already unreachable, so the sweep can be re-run.
+    pub async fn sweep(db: &Database, apply: bool) -> Result<OrphanReport, AppError> {
+        let mut report = OrphanReport {
+            applied: apply,
+            ..OrphanReport::default()
+        };
+        let posts = db.collection("blog_posts");
+        let comments = db.collection("comments");
+
+        let blog_ids = object_ids(
+            comments
+                .distinct("blog_id", None, None)
+                .await
+                .map_err(AppError::database_error)?,
+        );
+        report.missing_posts = missing(&posts, blog_ids).await?;
+        let orphaned = doc! { "blog_id": { "$in": &report.missing_posts } };
+        report.orphan_comments = if apply {
+            comments
+                .delete_many(orphaned, None)
+                .await
+                .map_err(AppError::database_error)?
+                .deleted_count
+        } else {
+            comments
+                .count_documents(orphaned, None)
+                .await
+                .map_err(AppError::database_error)?
+        };
+
+        let references = [
+            (&posts, "user_id"),
+            (&posts, "upvotes.users"),
+            (&posts, "downvotes.users"),
+            (&comments, "user_id"),
+            (&comments, "likes.users"),
+            (&comments, "dislikes.users"),
+            (&comments, "replies.user_id"),
+            (&comments, "replies.likes.users"),
+            (&comments, "replies.dislikes.users"),
+        ];
+        let mut user_ids: Vec<ObjectId> = vec![];
+        for (coll, field) in references.iter() {
+            for id in object_ids(coll.distinct(field, None, None).await.map_err(AppError::database_error)?) {
+                if id != deleted_user_id() && !user_ids.contains(&id) {
+                    user_ids.push(id);
+                }
+            }
+        }
+        report.missing_users = missing(&db.collection("users"), user_ids).await?;
+        if apply {
+            for user_id in &report.missing_users {
+                Votes::remove_user(db, user_id).await?;
+                BlogPost::anonymize_user(db, user_id).await?;
+                Comments::anonymize_user(db, user_id).await?;
+            }
+        }
+
+        report.stale_avatars = stale_avatars(db).await?;
+        if apply {
+            for path in &report.stale_avatars {
+                s3_aws::delete_file(s3_aws::get_s3_bucket().await, path.as_str()).await?;
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+async fn stale_avatars(db: &Database) -> Result<Vec<String>, AppError> {
+    let in_use: Vec<String> = db
+        .collection("users")
+        .distinct("user_avatar", None, None)
+        .await
+        .map_err(AppError::database_error)?
+        .into_iter()
+        .filter_map(|url| match url {
+            Bson::String(url) => s3_aws::object_path(url.as_str()).map(str::to_string),
+            _ => None,
+        })
+        .collect();
+    let cutoff = Utc::now() - Duration::seconds(AVATAR_GRACE_SECS);
+
+    let mut stale = vec![];
+    let bucket = s3_aws::get_s3_bucket().await;
+    for object in s3_aws::list_files(bucket, s3_aws::AVATAR_PREFIX).await? {
+        let path = format!("/{}", object.key);
+        let settled = DateTime::parse_from_rfc3339(object.last_modified.as_str())
+            .is_ok_and(|modified| modified < cutoff);
+        if settled && !in_use.contains(&path) {
+            stale.push(path);
+        }
+    }
+    Ok(stale)
+}
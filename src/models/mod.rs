@@ -1,2 +1,12 @@
+pub mod api_key;
+pub mod audit_log;
 pub mod blogs;
+pub mod export;
+pub mod login_attempt;
+pub mod maintenance;
+pub mod oidc_login;
+pub mod pagination;
+pub mod password_reset;
+pub mod session;
+pub mod token;
 pub mod user;
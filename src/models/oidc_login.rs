@@ -0,0 +1,98 @@
+use bson::{doc, DateTime};
+use chrono::{Duration, Utc};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::crypto::CryptoService,
+    errors::{AppError, AppErrorType},
+};
+
+/// How long a user has to finish signing in at the provider.
+const LOGIN_TTL_MINUTES: i64 = 10;
+
+fn get_coll(db: &Database) -> Collection {
+    db.collection("oidc_logins")
+}
+
+/// An authorization request in flight, keyed by its `state` parameter. It
+/// holds the PKCE verifier and nonce needed to complete the login, and the
+/// hash of a cookie that ties it to the browser that started it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OidcLogin {
+    #[serde(rename = "_id")]
+    pub state: String,
+    pub provider: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub browser: String,
+    pub expires_at: DateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: String,
+    pub error: Option<String>,
+}
+
+impl OidcLogin {
+    /// Starts a login for the browser holding `browser`, the value of the
+    /// binding cookie.
+    pub async fn create(
+        db: &Database,
+        provider: &str,
+        code_verifier: String,
+        browser: &str,
+    ) -> Result<OidcLogin, AppError> {
+        let login = OidcLogin {
+            state: CryptoService::generate_token(32),
+            provider: provider.to_string(),
+            nonce: CryptoService::generate_token(32),
+            code_verifier,
+            browser: CryptoService::hash_token(browser),
+            expires_at: DateTime(Utc::now() + Duration::minutes(LOGIN_TTL_MINUTES)),
+        };
+
+        get_coll(db)
+            .insert_one(bson::to_document(&login).unwrap(), None)
+            .await
+            .map_err(AppError::database_error)?;
+        Ok(login)
+    }
+
+    /// Removes and returns the login for `state`, so a callback can only be
+    /// completed once. It must arrive from the browser that started it, or
+    /// a callback URL handed to someone else would sign them in as whoever
+    /// authorized it at the provider.
+    pub async fn consume(
+        db: &Database,
+        provider: &str,
+        state: &str,
+        browser: Option<&str>,
+    ) -> Result<OidcLogin, AppError> {
+        let invalid_state = || AppError {
+            cause: Some("INVALID_STATE".to_string()),
+            message: Some("Sign-in request is invalid or expired, start again".to_string()),
+            error_type: AppErrorType::InvalidToken,
+        };
+        let browser = browser.ok_or_else(invalid_state)?;
+        let doc = get_coll(db)
+            .find_one_and_delete(
+                doc! {
+                    "_id": state,
+                    "provider": provider,
+                    "browser": CryptoService::hash_token(browser),
+                    "expires_at": { "$gt": Utc::now() }
+                },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        match doc {
+            Some(doc) => bson::from_document(doc).map_err(|_e| invalid_state()),
+            None => Err(invalid_state()),
+        }
+    }
+}
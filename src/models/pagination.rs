@@ -0,0 +1,257 @@
+use bson::{doc, oid::ObjectId, Bson, Document};
+use futures::StreamExt;
+use mongodb::{options::FindOptions, Collection};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::errors::{AppError, AppErrorType};
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+/// `limit` and `after` of the list endpoints. `after` is the `next_cursor`
+/// of the previous page.
+#[derive(Deserialize, Debug)]
+pub struct PageQuery {
+    pub limit: Option<i64>,
+    pub after: Option<String>,
+}
+
+/// One page of a listing. `next_cursor` is absent on the last page.
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+/// Order of a listing: an optional numeric field such as `upvotes.count`,
+/// then `_id` in the same direction so the order is total.
+///
+/// Ordering by `_id` alone is stable: a walk through the pages sees every
+/// item once. Ordering by a field is only as stable as the field. An item
+/// whose count changes between two pages moves in the ranking, so it can
+/// show up twice or be missed in that walk.
+#[derive(Clone, Copy, Debug)]
+pub struct SortOrder {
+    pub field: Option<&'static str>,
+    pub descending: bool,
+}
+
+/// What a cursor encodes: the order it was issued for and the sort key of
+/// the last item handed out. With a field order, `v` is `None` when that
+/// item had no value, which MongoDB sorts below every number.
+#[derive(Serialize, Deserialize, Debug)]
+struct Cursor {
+    f: Option<String>,
+    d: bool,
+    v: Option<i64>,
+    id: String,
+}
+
+fn invalid_cursor() -> AppError {
+    AppError {
+        cause: Some("INVALID_CURSOR".to_string()),
+        message: Some("The cursor is malformed or belongs to another listing".to_string()),
+        error_type: AppErrorType::BadRequest,
+    }
+}
+
+/// Reads a numeric value at a dotted path.
+fn number_at(doc: &Document, path: &str) -> Option<i64> {
+    let mut parts = path.splitn(2, '.');
+    let head = parts.next().unwrap_or_default();
+    match (doc.get(head), parts.next()) {
+        (Some(Bson::Document(inner)), Some(rest)) => number_at(inner, rest),
+        (Some(Bson::Int32(n)), None) => Some(*n as i64),
+        (Some(Bson::Int64(n)), None) => Some(*n),
+        (Some(Bson::Double(n)), None) => Some(*n as i64),
+        _ => None,
+    }
+}
+
+impl SortOrder {
+    pub fn by_id(descending: bool) -> Self {
+        SortOrder {
+            field: None,
+            descending,
+        }
+    }
+
+    pub fn by_field(field: &'static str, descending: bool) -> Self {
+        SortOrder {
+            field: Some(field),
+            descending,
+        }
+    }
+
+    fn sort(&self) -> Document {
+        let direction = if self.descending { -1 } else { 1 };
+        let mut sort = Document::new();
+        if let Some(field) = self.field {
+            sort.insert(field, direction);
+        }
+        sort.insert("_id", direction);
+        sort
+    }
+
+    fn encode(&self, doc: &Document) -> Option<String> {
+        let cursor = Cursor {
+            f: self.field.map(str::to_string),
+            d: self.descending,
+            v: self.field.and_then(|field| number_at(doc, field)),
+            id: doc.get_object_id("_id").ok()?.to_hex(),
+        };
+        let json = serde_json::to_vec(&cursor).ok()?;
+        Some(base64::encode_config(json, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Everything strictly after the cursor in this order. Missing values
+    /// match `null` and come before all numbers ascending, after them
+    /// descending.
+    fn after(&self, encoded: &str) -> Result<Document, AppError> {
+        let json = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)
+            .map_err(|_e| invalid_cursor())?;
+        let cursor: Cursor = serde_json::from_slice(&json).map_err(|_e| invalid_cursor())?;
+        if cursor.f.as_deref() != self.field || cursor.d != self.descending {
+            return Err(invalid_cursor());
+        }
+        let id = ObjectId::with_string(cursor.id.as_str()).map_err(|_e| invalid_cursor())?;
+        let op = if self.descending { "$lt" } else { "$gt" };
+
+        match (self.field, cursor.v) {
+            (Some(field), Some(value)) => {
+                let mut after = vec![
+                    doc! { field: { op: value } },
+                    doc! { field: value, "_id": { op: &id } },
+                ];
+                if self.descending {
+                    after.push(doc! { field: Bson::Null });
+                }
+                Ok(doc! { "$or": after })
+            }
+            (Some(field), None) if self.descending => Ok(doc! {
+                field: Bson::Null, "_id": { "$lt": &id }
+            }),
+            (Some(field), None) => Ok(doc! {
+                "$or": [
+                    { field: Bson::Null, "_id": { "$gt": &id } },
+                    { field: { "$ne": Bson::Null } }
+                ]
+            }),
+            (None, None) => Ok(doc! { "_id": { op: &id } }),
+            (None, Some(_)) => Err(invalid_cursor()),
+        }
+    }
+}
+
+/// Loads the page of `coll` matching `filter` that `query` asks for. One
+/// extra document is fetched to tell whether another page follows.
+pub async fn find_page<T: DeserializeOwned>(
+    coll: &Collection,
+    filter: Document,
+    order: SortOrder,
+    query: &PageQuery,
+) -> Result<Page<T>, AppError> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let filter = match &query.after {
+        Some(after) => {
+            let after = order.after(after.as_str())?;
+            doc! { "$and": [filter, after] }
+        }
+        None => filter,
+    };
+    let options = FindOptions::builder()
+        .sort(order.sort())
+        .limit(limit + 1)
+        .build();
+    let mut cur = coll.find(filter, options).await.map_err(AppError::database_error)?;
+
+    let mut docs: Vec<Document> = vec![];
+    while let Some(doc) = cur.next().await {
+        docs.push(doc.map_err(AppError::database_error)?);
+    }
+
+    let next_cursor = if docs.len() as i64 > limit {
+        docs.truncate(limit as usize);
+        docs.last().and_then(|doc| order.encode(doc))
+    } else {
+        None
+    };
+    let items = docs
+        .into_iter()
+        .map(|doc| bson::from_document(doc).map_err(AppError::decode_error))
+        .collect::<Result<_, _>>()?;
+    Ok(Page { items, next_cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(id: &ObjectId, upvotes: Option<i32>) -> Document {
+        match upvotes {
+            Some(count) => doc! { "_id": id, "upvotes": { "count": count } },
+            None => doc! { "_id": id },
+        }
+    }
+
+    #[test]
+    fn cursor_round_trips_into_an_after_filter() {
+        let id = ObjectId::new();
+        let order = SortOrder::by_id(true);
+        let cursor = order.encode(&post(&id, None)).unwrap();
+
+        assert_eq!(order.after(cursor.as_str()).unwrap(), doc! { "_id": { "$lt": &id } });
+        assert_eq!(
+            SortOrder::by_id(false).after(cursor.as_str()).unwrap_err().code(),
+            "INVALID_CURSOR"
+        );
+    }
+
+    #[test]
+    fn field_cursor_continues_after_ties_and_missing_values() {
+        let id = ObjectId::new();
+        let order = SortOrder::by_field("upvotes.count", true);
+
+        let cursor = order.encode(&post(&id, Some(0))).unwrap();
+        assert_eq!(
+            order.after(cursor.as_str()).unwrap(),
+            doc! {
+                "$or": [
+                    { "upvotes.count": { "$lt": 0_i64 } },
+                    { "upvotes.count": 0_i64, "_id": { "$lt": &id } },
+                    { "upvotes.count": Bson::Null }
+                ]
+            }
+        );
+
+        let cursor = order.encode(&post(&id, None)).unwrap();
+        assert_eq!(
+            order.after(cursor.as_str()).unwrap(),
+            doc! { "upvotes.count": Bson::Null, "_id": { "$lt": &id } }
+        );
+
+        let ascending = SortOrder::by_field("upvotes.count", false);
+        let cursor = ascending.encode(&post(&id, Some(3))).unwrap();
+        assert_eq!(
+            ascending.after(cursor.as_str()).unwrap(),
+            doc! {
+                "$or": [
+                    { "upvotes.count": { "$gt": 3_i64 } },
+                    { "upvotes.count": 3_i64, "_id": { "$gt": &id } }
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn foreign_or_garbled_cursors_are_rejected() {
+        let id = ObjectId::new();
+        let by_votes = SortOrder::by_field("upvotes.count", true);
+        let cursor = by_votes.encode(&post(&id, Some(2))).unwrap();
+
+        assert!(SortOrder::by_field("likes.count", true).after(cursor.as_str()).is_err());
+        assert!(SortOrder::by_id(true).after(cursor.as_str()).is_err());
+        assert!(by_votes.after("not a cursor").is_err());
+        assert!(by_votes.after(&cursor[1..]).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use bson::{doc, oid::ObjectId, DateTime, Document};
+use chrono::{Duration, Utc};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::crypto::CryptoService,
+    errors::{AppError, AppErrorType},
+};
+
+const RESET_TTL_MINUTES: i64 = 30;
+const MAX_ATTEMPTS: i32 = 5;
+
+fn get_coll(db: &Database) -> Collection {
+    db.collection("password_resets")
+}
+
+/// Pending password reset. Only the SHA-256 of the emailed token is stored and
+/// a reset stops accepting guesses after `MAX_ATTEMPTS` wrong ones.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PasswordReset {
+    #[serde(rename = "_id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub token_hash: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    pub attempts: i32,
+    pub used: bool,
+}
+
+/// A recovery code to check before the new password is asked for. It is
+/// sent in the body so it stays out of URLs and the logs that record them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckRecovery {
+    pub email: String,
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResetPassword {
+    pub email: String,
+    pub token: String,
+    pub password: String,
+}
+
+pub fn invalid_token() -> AppError {
+    AppError {
+        cause: Some("INVALID_TOKEN".to_string()),
+        message: Some("Invalid or expired recovery code".to_string()),
+        error_type: AppErrorType::InvalidToken,
+    }
+}
+
+fn valid_filter(user_id: &ObjectId, token: &str) -> Document {
+    doc! {
+        "user_id": user_id,
+        "token_hash": CryptoService::hash_token(token),
+        "used": false,
+        "attempts": { "$lt": MAX_ATTEMPTS },
+        "expires_at": { "$gt": Utc::now() }
+    }
+}
+
+impl PasswordReset {
+    /// Starts a reset for `user_id`, superseding any earlier one, and returns
+    /// the plaintext token to email.
+    pub async fn create(db: &Database, user_id: &ObjectId) -> Result<String, AppError> {
+        let coll = get_coll(db);
+
+        coll.update_many(
+            doc! { "user_id": user_id, "used": false },
+            doc! { "$set": { "used": true } },
+            None,
+        )
+        .await
+        .map_err(AppError::database_error)?;
+
+        let token = CryptoService::generate_token(32);
+        let reset = PasswordReset {
+            id: None,
+            user_id: user_id.clone(),
+            token_hash: CryptoService::hash_token(token.as_str()),
+            created_at: DateTime(Utc::now()),
+            expires_at: DateTime(Utc::now() + Duration::minutes(RESET_TTL_MINUTES)),
+            attempts: 0,
+            used: false,
+        };
+
+        coll.insert_one(bson::to_document(&reset).unwrap(), None)
+            .await
+            .map_err(AppError::database_error)?;
+
+        Ok(token)
+    }
+
+    /// Checks `token` without consuming it, counting a miss as an attempt.
+    pub async fn verify(db: &Database, user_id: &ObjectId, token: &str) -> Result<(), AppError> {
+        let found = get_coll(db)
+            .find_one(valid_filter(user_id, token), None)
+            .await
+            .map_err(AppError::database_error)?;
+
+        if found.is_some() {
+            return Ok(());
+        }
+        PasswordReset::record_failure(db, user_id).await?;
+        Err(invalid_token())
+    }
+
+    /// Marks the reset used in the same operation that matches the token, so
+    /// a token can only ever be redeemed once.
+    pub async fn consume(db: &Database, user_id: &ObjectId, token: &str) -> Result<(), AppError> {
+        let claimed = get_coll(db)
+            .find_one_and_update(
+                valid_filter(user_id, token),
+                doc! { "$set": { "used": true } },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        if claimed.is_some() {
+            return Ok(());
+        }
+        PasswordReset::record_failure(db, user_id).await?;
+        Err(invalid_token())
+    }
+
+    async fn record_failure(db: &Database, user_id: &ObjectId) -> Result<(), AppError> {
+        get_coll(db)
+            .update_many(
+                doc! { "user_id": user_id, "used": false },
+                doc! { "$inc": { "attempts": 1 } },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+        Ok(())
+    }
+}
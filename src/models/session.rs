@@ -0,0 +1,197 @@
+use bson::{doc, oid::ObjectId, DateTime};
+use chrono::Utc;
+use futures::StreamExt;
+use mongodb::{
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{AppError, AppErrorType},
+    models::token::RefreshToken,
+};
+
+fn get_coll(db: &Database) -> Collection {
+    db.collection("sessions")
+}
+
+/// A login on one device. The session id doubles as the refresh token family
+/// and is carried in the `sid` claim of every access token issued for it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Session {
+    #[serde(rename = "_id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    pub created_at: DateTime,
+    pub last_seen_at: DateTime,
+    pub revoked: bool,
+}
+
+async fn convert_obj_id(id: &str) -> Result<ObjectId, AppError> {
+    match ObjectId::with_string(id) {
+        Ok(val) => Ok(val),
+        Err(_e) => Err(AppError {
+            cause: Some(_e.to_string()),
+            message: None,
+            error_type: AppErrorType::InvalidId,
+        }),
+    }
+}
+
+impl Session {
+    pub async fn create(
+        db: &Database,
+        user_id: &ObjectId,
+        user_agent: Option<String>,
+        ip: Option<String>,
+    ) -> Result<ObjectId, AppError> {
+        let session = Session {
+            id: None,
+            user_id: user_id.clone(),
+            user_agent,
+            ip,
+            created_at: DateTime(Utc::now()),
+            last_seen_at: DateTime(Utc::now()),
+            revoked: false,
+        };
+
+        let res = get_coll(db)
+            .insert_one(bson::to_document(&session).unwrap(), None)
+            .await
+            .map_err(AppError::database_error)?;
+
+        Ok(res.inserted_id.as_object_id().unwrap().clone())
+    }
+
+    pub async fn is_active(db: &Database, session_id: &str) -> Result<bool, AppError> {
+        let session = get_coll(db)
+            .find_one(
+                doc! {
+                    "_id": convert_obj_id(session_id).await?,
+                    "revoked": false
+                },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        Ok(session.is_some())
+    }
+
+    /// Records the address a session was last refreshed from and returns the
+    /// updated session.
+    pub async fn touch(
+        db: &Database,
+        session_id: &ObjectId,
+        ip: Option<String>,
+    ) -> Result<Session, AppError> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let session = get_coll(db)
+            .find_one_and_update(
+                doc! { "_id": session_id },
+                doc! {
+                    "$set": {
+                        "last_seen_at": Utc::now(),
+                        "ip": bson::to_bson(&ip).unwrap()
+                    }
+                },
+                options,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        match session {
+            Some(doc) => bson::from_document(doc).map_err(AppError::decode_error),
+            None => Err(AppError {
+                cause: None,
+                message: Some("No Session Found".to_string()),
+                error_type: AppErrorType::NotFoundError,
+            }),
+        }
+    }
+
+    pub async fn get_active_by_user(
+        db: &Database,
+        user_id: &str,
+    ) -> Result<Vec<Session>, AppError> {
+        let options = FindOptions::builder()
+            .sort(doc! {"last_seen_at": -1 })
+            .build();
+        let mut cur = get_coll(db)
+            .find(
+                doc! {
+                    "user_id": convert_obj_id(user_id).await?,
+                    "revoked": false
+                },
+                options,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        let mut res: Vec<Session> = vec![];
+        while let Some(doc) = cur.next().await {
+            res.push(bson::from_document(doc.map_err(AppError::database_error)?).map_err(AppError::decode_error)?);
+        }
+        Ok(res)
+    }
+
+    /// Marks the session revoked and invalidates its refresh tokens.
+    pub async fn end(db: &Database, session_id: &ObjectId) -> Result<(), AppError> {
+        get_coll(db)
+            .update_one(
+                doc! { "_id": session_id },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        RefreshToken::revoke_family(db, session_id).await
+    }
+
+    /// Ends one of `user_id`'s sessions, refusing ids that belong to others.
+    pub async fn revoke(db: &Database, user_id: &str, session_id: &str) -> Result<(), AppError> {
+        let session_id = convert_obj_id(session_id).await?;
+        let session = get_coll(db)
+            .find_one(
+                doc! {
+                    "_id": &session_id,
+                    "user_id": convert_obj_id(user_id).await?
+                },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        if session.is_none() {
+            return Err(AppError {
+                cause: None,
+                message: Some("No Session Found".to_string()),
+                error_type: AppErrorType::NotFoundError,
+            });
+        }
+
+        Session::end(db, &session_id).await
+    }
+
+    pub async fn revoke_all(db: &Database, user_id: &ObjectId) -> Result<(), AppError> {
+        get_coll(db)
+            .update_many(
+                doc! { "user_id": user_id },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        RefreshToken::revoke_user(db, user_id).await
+    }
+}
@@ -0,0 +1,166 @@
+use bson::{doc, oid::ObjectId, DateTime};
+use chrono::{Duration, Utc};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::crypto::CryptoService,
+    errors::{AppError, AppErrorType},
+    models::session::Session,
+};
+
+fn get_coll(db: &Database) -> Collection {
+    db.collection("refresh_tokens")
+}
+
+/// Opaque refresh token as stored in Mongo. Only the SHA-256 of the token is
+/// kept; every token minted by rotating another shares its `family`, which is
+/// the id of the `Session` it was issued for.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RefreshToken {
+    #[serde(rename = "_id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub family: ObjectId,
+    pub token_hash: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    pub used: bool,
+    pub revoked: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+fn invalid_token(cause: &str) -> AppError {
+    AppError {
+        cause: Some(cause.to_string()),
+        message: Some("Invalid Refresh Token".to_string()),
+        error_type: AppErrorType::JWtTokenError,
+    }
+}
+
+impl RefreshToken {
+    /// Stores a new token for `user_id` and returns its plaintext value, which
+    /// is never persisted.
+    pub async fn issue(
+        db: &Database,
+        user_id: &ObjectId,
+        family: &ObjectId,
+        ttl: i64,
+    ) -> Result<String, AppError> {
+        let token = CryptoService::generate_token(64);
+
+        let record = RefreshToken {
+            id: None,
+            user_id: user_id.clone(),
+            family: family.clone(),
+            token_hash: CryptoService::hash_token(token.as_str()),
+            created_at: DateTime(Utc::now()),
+            expires_at: DateTime(Utc::now() + Duration::seconds(ttl)),
+            used: false,
+            revoked: false,
+        };
+
+        get_coll(db)
+            .insert_one(bson::to_document(&record).unwrap(), None)
+            .await
+            .map_err(AppError::database_error)?;
+
+        Ok(token)
+    }
+
+    /// Consumes `token` and returns the record it belonged to.
+    ///
+    /// Presenting a token that was already rotated is treated as theft: the
+    /// whole session is ended so neither party can keep refreshing.
+    pub async fn consume(db: &Database, token: &str) -> Result<RefreshToken, AppError> {
+        let coll = get_coll(db);
+        let token_hash = CryptoService::hash_token(token);
+
+        let claimed = coll
+            .find_one_and_update(
+                doc! {
+                    "token_hash": token_hash.as_str(),
+                    "used": false,
+                    "revoked": false,
+                    "expires_at": { "$gt": Utc::now() }
+                },
+                doc! {
+                    "$set": { "used": true }
+                },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        if let Some(doc) = claimed {
+            return bson::from_document(doc).map_err(AppError::decode_error);
+        }
+
+        let existing = coll
+            .find_one(doc! { "token_hash": token_hash.as_str() }, None)
+            .await
+            .map_err(AppError::database_error)?;
+
+        match existing {
+            None => Err(invalid_token("UNKNOWN_REFRESH_TOKEN")),
+            Some(doc) => {
+                let record: RefreshToken = bson::from_document(doc).map_err(AppError::decode_error)?;
+                if record.used {
+                    Session::end(db, &record.family).await?;
+                    return Err(invalid_token("REFRESH_TOKEN_REUSED"));
+                }
+                if record.revoked {
+                    return Err(invalid_token("REFRESH_TOKEN_REVOKED"));
+                }
+                Err(invalid_token("REFRESH_TOKEN_EXPIRED"))
+            }
+        }
+    }
+
+    pub async fn revoke_family(db: &Database, family: &ObjectId) -> Result<(), AppError> {
+        get_coll(db)
+            .update_many(
+                doc! { "family": family },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+        Ok(())
+    }
+
+    pub async fn revoke_user(db: &Database, user_id: &ObjectId) -> Result<(), AppError> {
+        get_coll(db)
+            .update_many(
+                doc! { "user_id": user_id },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+        Ok(())
+    }
+
+    /// Ends the session `token` belongs to. Unknown tokens are ignored so
+    /// logging out twice is harmless.
+    pub async fn revoke(db: &Database, token: &str) -> Result<(), AppError> {
+        let existing = get_coll(db)
+            .find_one(
+                doc! { "token_hash": CryptoService::hash_token(token) },
+                None,
+            )
+            .await
+            .map_err(AppError::database_error)?;
+
+        if let Some(doc) = existing {
+            let record: RefreshToken = bson::from_document(doc).map_err(AppError::decode_error)?;
+            Session::end(db, &record.family).await?;
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,23 @@
 use crate::{
-    config::crypto::CryptoService,
+    config::{
+        crypto::{CryptoService, HashConfig},
+        oidc::IdTokenClaims,
+        s3_aws,
+        totp::{Totp, TotpKey},
+    },
     errors::{AppError, AppErrorType},
+    models::{
+        api_key::ApiKey,
+        blogs::{BlogPost, Comments, Votes},
+        pagination::{find_page, Page, PageQuery, SortOrder},
+        session::Session,
+    },
 };
 
 use bson;
-use futures::StreamExt;
+use chrono::{Duration, Utc};
 use mongodb::{
-    bson::{doc, oid::ObjectId},
+    bson::{doc, oid::ObjectId, DateTime},
     Collection, Database,
 };
 use serde::{Deserialize, Serialize};
@@ -15,16 +26,187 @@ fn get_coll(db: &Database) -> Collection {
     db.collection("users")
 }
 
+/// Whether a write failed on the unique email index.
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    use mongodb::error::{ErrorKind, WriteFailure};
+    match err.kind.as_ref() {
+        ErrorKind::WriteError(WriteFailure::WriteError(failure)) => failure.code == 11000,
+        ErrorKind::CommandError(failure) => failure.code == 11000,
+        _ => false,
+    }
+}
+
+fn email_taken() -> AppError {
+    AppError {
+        cause: Some("EMAIL_TAKEN".to_string()),
+        message: Some("Another account already uses this email address".to_string()),
+        error_type: AppErrorType::AlreadyExists,
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UserCreds {
     pub email: String,
     pub password: String,
 }
 
+/// The fields a sign-up may set. Everything else about a new account starts
+/// at its default, whatever else the request carries.
+#[derive(Deserialize, Debug)]
+pub struct SignUp {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+}
+
+impl From<SignUp> for User {
+    fn from(sign_up: SignUp) -> User {
+        User {
+            id: None,
+            username: sign_up.username,
+            email: sign_up.email,
+            password: sign_up.password,
+            user_avatar: None,
+            role: Role::default(),
+            token_version: 0,
+            email_verified: false,
+            pending_email: None,
+            totp_enabled: false,
+            totp_secret: None,
+            recovery_codes: Vec::new(),
+            status: AccountStatus::default(),
+            suspended_until: None,
+            identities: Vec::new(),
+        }
+    }
+}
+
+/// Number of single-use recovery codes issued when two-factor is enabled.
+const RECOVERY_CODES: usize = 10;
+
+/// Longest suspension an admin can hand out; anything longer is a ban.
+const MAX_SUSPENSION_DAYS: i64 = 365;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TwoFactorLogin {
+    pub challenge: String,
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+/// Turning two-factor off needs a current code and, like other sensitive
+/// changes, the password or a recent sign-in.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DisableTwoFactor {
+    pub code: String,
+    #[serde(default)]
+    pub current_password: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PatchUser {
     pub email: String,
     pub username: String,
+    #[serde(default)]
+    pub current_password: Option<String>,
+}
+
+/// Ordered from least to most privileged, so `>=` compares privilege.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Reader,
+    #[default]
+    Author,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn can_moderate(&self) -> bool {
+        *self >= Role::Moderator
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Suspended,
+    Banned,
+}
+
+impl AccountStatus {
+    /// Rejects banned accounts and accounts whose suspension has not yet run
+    /// out. A lapsed suspension needs no cleanup to stop applying.
+    pub fn ensure_active(&self, suspended_until: Option<&DateTime>) -> Result<(), AppError> {
+        match self {
+            AccountStatus::Banned => Err(AppError {
+                cause: Some("ACCOUNT_BANNED".to_string()),
+                message: Some("This account has been banned".to_string()),
+                error_type: AppErrorType::ForbiddenError,
+            }),
+            AccountStatus::Suspended => match suspended_until {
+                Some(until) if until.0 <= Utc::now() => Ok(()),
+                _ => Err(AppError {
+                    cause: Some("ACCOUNT_SUSPENDED".to_string()),
+                    message: Some("This account is suspended".to_string()),
+                    error_type: AppErrorType::ForbiddenError,
+                }),
+            },
+            AccountStatus::Active => Ok(()),
+        }
+    }
+}
+
+/// An account at an external OpenID Connect provider linked to a user.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Identity {
+    pub provider: String,
+    pub subject: String,
+}
+
+/// What happens to a deleted account's posts, comments and replies: kept
+/// under a placeholder author, or deleted along with it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletionMode {
+    #[default]
+    Anonymize,
+    Cascade,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteAccount {
+    #[serde(default)]
+    pub mode: DeletionMode,
+    #[serde(default)]
+    pub current_password: Option<String>,
+}
+
+/// Collections of per-user records, keyed by `user_id`, that go with the
+/// account when it is deleted.
+const ACCOUNT_RECORDS: &[&str] = &[
+    "sessions",
+    "refresh_tokens",
+    "api_keys",
+    "password_resets",
+    "lockouts",
+];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetRole {
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Suspension {
+    pub days: i64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -38,8 +220,26 @@ pub struct User {
     pub password: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user_avatar: Option<String>,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub token_version: i32,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_email: Option<String>,
+    #[serde(default)]
+    pub totp_enabled: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub recovery: Option<i32>
+    pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    #[serde(default)]
+    pub status: AccountStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suspended_until: Option<DateTime>,
+    #[serde(default)]
+    pub identities: Vec<Identity>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -53,24 +253,66 @@ pub struct UserDetails {
     pub password: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user_avatar: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub recovery: Option<i32>
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default, skip_serializing)]
+    pub token_version: i32,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default, skip_serializing)]
+    pub pending_email: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub totp_enabled: bool,
+    #[serde(default, skip_serializing)]
+    pub totp_secret: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub recovery_codes: Vec<String>,
+    #[serde(default)]
+    pub status: AccountStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspended_until: Option<DateTime>,
+    #[serde(default, skip_serializing)]
+    pub identities: Vec<Identity>,
 }
 
 impl User {
-    pub async fn save(&mut self, db: &Database) -> Result<(), AppError> {
-        let coll = get_coll(&db);
-        self.password = CryptoService::hash_password(self.password.clone()).await?;
-        self.user_avatar = Some(format!(
-            "https://test-blog-static.s3.ap-south-1.amazonaws.com{}",
-            &self.user_avatar.as_ref().unwrap()
-        ));
+    /// Makes email addresses unique across accounts, so two accounts can
+    /// never end up confirming the same one, however their requests
+    /// interleave.
+    pub async fn create_indexes(db: &Database) -> Result<(), AppError> {
+        db.run_command(
+            doc! {
+                "createIndexes": "users",
+                "indexes": [{
+                    "key": { "email": 1 },
+                    "name": "email_unique",
+                    "unique": true
+                }]
+            },
+            None,
+        )
+        .await
+        .map_err(|_e| AppError {
+            cause: Some(_e.to_string()),
+            message: None,
+            error_type: AppErrorType::DatabaseError,
+        })?;
+        Ok(())
+    }
+
+    pub async fn save(&mut self, db: &Database, hash_config: &HashConfig) -> Result<(), AppError> {
+        let coll = get_coll(db);
+        self.password = CryptoService::hash_password(hash_config, self.password.clone()).await?;
 
         match coll
             .insert_one(bson::to_document(self).unwrap(), None)
             .await
         {
-            Ok(_) => Ok(()),
+            Ok(res) => {
+                self.id = res.inserted_id.as_object_id().cloned();
+                Ok(())
+            }
+            Err(_e) if is_duplicate_key(&_e) => Err(email_taken()),
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
                 message: None,
@@ -80,7 +322,7 @@ impl User {
     }
 
     pub async fn check_username(&self, db: &Database) -> Result<(), AppError> {
-        let coll = get_coll(&db);
+        let coll = get_coll(db);
         match coll
             .find_one(
                 doc! {
@@ -91,112 +333,25 @@ impl User {
             .await
         {
             Ok(val) => {
-                if !val.is_none() {
+                if val.is_some() {
                     return Err(AppError {
                         cause: Some("USERNAME_EXISTS".to_string()),
                         message: None,
-                        error_type: AppErrorType::ALREADYEXIST,
+                        error_type: AppErrorType::AlreadyExists,
                     });
                 }
-                return Ok(());
-            }
-            Err(_e) => Err(AppError {
-                cause: Some(_e.to_string()),
-                message: None,
-                error_type: AppErrorType::DatabaseError,
-            }),
-        }
-    }
-
-    pub async fn check_validiity(db: &Database, email: &str, data: i32) -> Result<(), AppError>{
-        let coll = get_coll(&db);
-
-        match coll.find_one(doc!{
-            "email": email,
-            "recovery": data
-        }, None).await {
-            Ok(val) => {
-                if val.is_some(){
-                    match coll.update_one(doc!{
-                        "email": email,
-                        "recovery": data
-                    }, doc! {
-                        "$unset": {
-                            "recovery": 1
-                        }
-                    }, None).await {
-                        Ok(_)  => Ok(()),
-                        Err(_e) => Err(AppError {
-                            cause: Some(_e.to_string()),
-                            message: None,
-                            error_type: AppErrorType::DatabaseError,
-                        }),
-                    }?; 
-                    return Ok(()) 
-                } else {
-                     Err(AppError {
-                        cause: Some("INVALID_TOKEN".to_string()),
-                        message: None,
-                       error_type: AppErrorType::InavlidToken,
-                    })
-                }
-            },
-            Err(_e) => Err(AppError {
-                cause: Some(_e.to_string()),
-                message: None,
-                error_type: AppErrorType::DatabaseError,
-            }),
-        }
-    }
-
-
-    pub async fn email_exists(email: &str, coll: &Collection) -> Result<(), AppError>{
-        match coll.find_one(doc!{
-            "email": email
-        }, None).await {
-            Ok(val) => {
-                if val.is_some(){
-                    Ok(())    
-                } else {
-                     Err(AppError {
-                        cause: Some("INVALID_TOKEN".to_string()),
-                        message: None,
-                       error_type: AppErrorType::InavlidToken,
-                    })
-                }
-            },
-            Err(_e) => Err(AppError {
-                cause: Some(_e.to_string()),
-                message: None,
-                error_type: AppErrorType::DatabaseError,
-            }),
-        }
-    }
-
-    pub async fn add_recovery(db: &Database, email: &str, data: i32) -> Result<(), AppError>{
-        let coll = get_coll(&db);
-
-        User::email_exists(&email, &coll).await?;
-
-        match coll.update_one(doc!{
-            "email": email
-        }, doc! {
-            "$set": {
-                "recovery": data
+                Ok(())
             }
-        }, None).await {
-            Ok(_) => Ok(()),
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
                 message: None,
                 error_type: AppErrorType::DatabaseError,
             }),
         }
-
     }
 
     pub async fn check_email(&self, db: &Database) -> Result<(), AppError> {
-        let coll = get_coll(&db);
+        let coll = get_coll(db);
 
         match coll
             .find_one(
@@ -208,14 +363,14 @@ impl User {
             .await
             {
             Ok(val) => {
-                if !val.is_none() {
+                if val.is_some() {
                     return Err(AppError {
                         cause: Some("EMAIL_EXISTS".to_string()),
                         message: None,
-                        error_type: AppErrorType::ALREADYEXIST,
+                        error_type: AppErrorType::AlreadyExists,
                     });
                 }
-                return Ok(());
+                Ok(())
             }
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
@@ -228,7 +383,7 @@ impl User {
     pub async fn get_user_by_id(db: &Database, uid: &str) -> Result<UserDetails, AppError> {
         match ObjectId::with_string(uid) {
             Ok(id) => {
-                let coll = get_coll(&db);
+                let coll = get_coll(db);
                 match coll
                     .find_one(
                         doc! {
@@ -246,7 +401,7 @@ impl User {
                                 error_type: AppErrorType::NotFoundError,
                             });
                         }
-                        let user = bson::from_document::<UserDetails>(user.unwrap()).unwrap();
+                        let user = bson::from_document::<UserDetails>(user.unwrap()).map_err(AppError::decode_error)?;
                         Ok(user)
                     }
                     Err(_e) => Err(AppError {
@@ -261,14 +416,14 @@ impl User {
                 Err(AppError {
                     cause: Some(_e.to_string()),
                     message: Some("Invalid Id".to_string()),
-                    error_type: AppErrorType::InavlidId,
+                    error_type: AppErrorType::InvalidId,
                 })
             }
         }
     }
 
     pub async fn get_user_by_email(db: &Database, email: &str) -> Result<User, AppError> {
-        let coll = get_coll(&db);
+        let coll = get_coll(db);
 
         match coll
             .find_one(
@@ -287,7 +442,7 @@ impl User {
                         error_type: AppErrorType::NotFoundError,
                     });
                 }
-                Ok(bson::from_document(user.unwrap()).unwrap())
+                bson::from_document(user.unwrap()).map_err(AppError::decode_error)
             }
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
@@ -297,10 +452,37 @@ impl User {
         }
     }
 
-    pub async fn get_users(db: &Database) -> Result<Vec<User>, AppError> {
-        let coll = get_coll(&db);
-        let mut cursor = match coll.find(None, None).await {
-            Ok(cur) => Ok(cur),
+    /// Newest accounts first.
+    pub async fn get_users(db: &Database, page: &PageQuery) -> Result<Page<UserDetails>, AppError> {
+        find_page(&get_coll(db), doc! {}, SortOrder::by_id(true), page).await
+    }
+
+    pub async fn change_password(
+        db: &Database,
+        hash_config: &HashConfig,
+        user_id: &str,
+        password: &str,
+    ) -> Result<(), AppError> {
+        let coll = get_coll(db);
+        let user_id = convert_obj_id(user_id).await?;
+        match coll
+            .update_one(
+                doc! {
+                    "_id": &user_id
+                },
+                doc! {
+                    "$set": {
+                        "password": CryptoService::hash_password(hash_config, password.to_string()).await?,
+                    },
+                    "$inc": {
+                        "token_version": 1
+                    }
+                },
+                None,
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
                 message: None,
@@ -308,58 +490,517 @@ impl User {
             }),
         }?;
 
-        let mut res: Vec<User> = vec![];
+        Session::revoke_all(db, &user_id).await?;
+        ApiKey::revoke_user(db, &user_id).await
+    }
+
+    /// Stores a fresh TOTP secret, encrypted with `key`, awaiting
+    /// confirmation. Two-factor stays off until `enable_totp` sees a code
+    /// generated from it.
+    pub async fn start_totp(db: &Database, key: &TotpKey, user_id: &str) -> Result<String, AppError> {
+        let secret = Totp::generate_secret();
+        let res = get_coll(db)
+            .update_one(
+                doc! {
+                    "_id": convert_obj_id(user_id).await?,
+                    "totp_enabled": { "$ne": true }
+                },
+                doc! {
+                    "$set": { "totp_secret": key.seal(user_id, secret.as_str()), "totp_enabled": false }
+                },
+                None,
+            )
+            .await
+            .map_err(|_e| AppError {
+                cause: Some(_e.to_string()),
+                message: None,
+                error_type: AppErrorType::DatabaseError,
+            })?;
 
-        while let Some(val) = cursor.next().await {
-            let doc: User = bson::from_document(val.unwrap()).unwrap();
-            res.push(doc);
+        if res.matched_count == 0 {
+            return Err(AppError {
+                cause: Some("TOTP_ALREADY_ENABLED".to_string()),
+                message: Some("Two-factor authentication is already enabled".to_string()),
+                error_type: AppErrorType::AlreadyExists,
+            });
         }
-        Ok(res)
+        Ok(secret)
     }
 
-    pub async fn change_password(
+    /// Turns two-factor on once `code` proves the authenticator is set up,
+    /// returning the plaintext recovery codes. Only their hashes are kept.
+    pub async fn enable_totp(
         db: &Database,
+        key: &TotpKey,
         user_id: &str,
-        password: &str,
-    ) -> Result<(), AppError> {
-        let coll = get_coll(&db);
-        match coll
+        code: &str,
+    ) -> Result<Vec<String>, AppError> {
+        let user = User::get_user_by_id(db, user_id).await?;
+        if user.totp_enabled {
+            return Err(AppError {
+                cause: Some("TOTP_ALREADY_ENABLED".to_string()),
+                message: Some("Two-factor authentication is already enabled".to_string()),
+                error_type: AppErrorType::AlreadyExists,
+            });
+        }
+        let secret = user
+            .totp_secret
+            .as_deref()
+            .and_then(|secret| key.open(user_id, secret))
+            .ok_or(AppError {
+                cause: Some("TOTP_NOT_STARTED".to_string()),
+                message: Some("Start two-factor setup first".to_string()),
+                error_type: AppErrorType::NotFoundError,
+            })?;
+        let step = Totp::matching_step(secret.as_str(), code).ok_or_else(invalid_code)?;
+
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| CryptoService::generate_token(10))
+            .collect();
+        let hashes: Vec<String> = codes
+            .iter()
+            .map(|code| CryptoService::hash_token(code))
+            .collect();
+
+        get_coll(db)
             .update_one(
                 doc! {
-                    "_id": convert_obj_id(user_id).await?
+                    "_id": user.id.as_ref().unwrap()
                 },
                 doc! {
                     "$set": {
-                        "password": CryptoService::hash_password(password.to_string()).await?,
+                        "totp_enabled": true,
+                        "totp_last_step": step,
+                        "recovery_codes": hashes
                     }
                 },
                 None,
             )
             .await
+            .map_err(|_e| AppError {
+                cause: Some(_e.to_string()),
+                message: None,
+                error_type: AppErrorType::DatabaseError,
+            })?;
+        Ok(codes)
+    }
+
+    pub async fn disable_totp(db: &Database, user_id: &str) -> Result<(), AppError> {
+        get_coll(db)
+            .update_one(
+                doc! {
+                    "_id": convert_obj_id(user_id).await?
+                },
+                doc! {
+                    "$set": { "totp_enabled": false },
+                    "$unset": { "totp_secret": 1, "totp_last_step": 1, "recovery_codes": 1 }
+                },
+                None,
+            )
+            .await
+            .map_err(|_e| AppError {
+                cause: Some(_e.to_string()),
+                message: None,
+                error_type: AppErrorType::DatabaseError,
+            })?;
+        Ok(())
+    }
+
+    /// Checks a second factor for a user with two-factor enabled. `code` is
+    /// either a current TOTP code, accepted once per time step, or one of the
+    /// recovery codes, which is removed as it is used.
+    pub async fn verify_second_factor(
+        db: &Database,
+        key: &TotpKey,
+        user: &UserDetails,
+        code: &str,
+    ) -> Result<(), AppError> {
+        let coll = get_coll(db);
+        let user_id = user.id.as_ref().unwrap();
+        let code = code.trim();
+
+        let res = match user
+            .totp_secret
+            .as_deref()
+            .and_then(|secret| key.open(user_id.to_hex().as_str(), secret))
+            .and_then(|secret| Totp::matching_step(secret.as_str(), code))
         {
-            Ok(_) => Ok(()),
+            Some(step) => coll.update_one(
+                doc! {
+                    "_id": user_id,
+                    "$or": [
+                        { "totp_last_step": { "$lt": step } },
+                        { "totp_last_step": { "$exists": false } }
+                    ]
+                },
+                doc! {
+                    "$set": { "totp_last_step": step }
+                },
+                None,
+            ),
+            None => coll.update_one(
+                doc! {
+                    "_id": user_id,
+                    "recovery_codes": CryptoService::hash_token(code)
+                },
+                doc! {
+                    "$pull": { "recovery_codes": CryptoService::hash_token(code) }
+                },
+                None,
+            ),
+        }
+        .await
+        .map_err(|_e| AppError {
+            cause: Some(_e.to_string()),
+            message: None,
+            error_type: AppErrorType::DatabaseError,
+        })?;
+
+        if res.modified_count == 0 {
+            return Err(invalid_code());
+        }
+        Ok(())
+    }
+
+    /// Marks `email` verified for the user. A confirmed `pending_email`
+    /// replaces the current address unless another account took it since
+    /// it was requested; anything else is rejected.
+    pub async fn verify_email(db: &Database, user_id: &str, email: &str) -> Result<(), AppError> {
+        let coll = get_coll(db);
+        let user_id = convert_obj_id(user_id).await?;
+
+        let res = match coll
+            .update_one(
+                doc! {
+                    "_id": &user_id,
+                    "pending_email": email
+                },
+                doc! {
+                    "$set": { "email": email, "email_verified": true },
+                    "$unset": { "pending_email": 1 }
+                },
+                None,
+            )
+            .await
+        {
+            Ok(res) => Ok(res),
+            Err(_e) if is_duplicate_key(&_e) => Err(email_taken()),
+            Err(_e) => Err(AppError {
+                cause: Some(_e.to_string()),
+                message: None,
+                error_type: AppErrorType::DatabaseError,
+            }),
+        }?;
+        if res.matched_count > 0 {
+            return Ok(());
+        }
+
+        let res = match coll
+            .update_one(
+                doc! {
+                    "_id": &user_id,
+                    "email": email
+                },
+                doc! {
+                    "$set": { "email_verified": true }
+                },
+                None,
+            )
+            .await
+        {
+            Ok(res) => Ok(res),
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
                 message: None,
                 error_type: AppErrorType::DatabaseError,
             }),
+        }?;
+        if res.matched_count == 0 {
+            return Err(AppError {
+                cause: Some("EMAIL_MISMATCH".to_string()),
+                message: Some("This link is for an address no longer on the account".to_string()),
+                error_type: AppErrorType::InvalidToken,
+            });
+        }
+        Ok(())
+    }
+
+    pub async fn set_role(db: &Database, user_id: &str, role: Role) -> Result<(), AppError> {
+        User::update_account(
+            db,
+            user_id,
+            doc! {
+                "$set": { "role": bson::to_bson(&role).unwrap() }
+            },
+        )
+        .await
+    }
+
+    /// Suspends the account for `days` days, or bans it when `days` is `None`,
+    /// and signs it out everywhere.
+    pub async fn restrict(db: &Database, user_id: &str, days: Option<i64>) -> Result<(), AppError> {
+        let update = match days {
+            Some(days) => {
+                let until = Some(days)
+                    .filter(|days| (1..=MAX_SUSPENSION_DAYS).contains(days))
+                    .and_then(|days| Utc::now().checked_add_signed(Duration::days(days)))
+                    .ok_or_else(|| {
+                        AppError::invalid_field(
+                            "days",
+                            "OUT_OF_RANGE",
+                            format!("Must be between 1 and {} days", MAX_SUSPENSION_DAYS).as_str(),
+                        )
+                    })?;
+                doc! {
+                    "$set": {
+                        "status": bson::to_bson(&AccountStatus::Suspended).unwrap(),
+                        "suspended_until": until
+                    }
+                }
+            }
+            None => doc! {
+                "$set": { "status": bson::to_bson(&AccountStatus::Banned).unwrap() },
+                "$unset": { "suspended_until": 1 }
+            },
+        };
+        User::update_account(db, user_id, update).await?;
+        User::revoke_tokens(db, user_id).await
+    }
+
+    pub async fn reinstate(db: &Database, user_id: &str) -> Result<(), AppError> {
+        User::update_account(
+            db,
+            user_id,
+            doc! {
+                "$set": { "status": bson::to_bson(&AccountStatus::Active).unwrap() },
+                "$unset": { "suspended_until": 1 }
+            },
+        )
+        .await
+    }
+
+    async fn update_account(db: &Database, user_id: &str, update: bson::Document) -> Result<(), AppError> {
+        let res = get_coll(db)
+            .update_one(
+                doc! {
+                    "_id": convert_obj_id(user_id).await?
+                },
+                update,
+                None,
+            )
+            .await
+            .map_err(|_e| AppError {
+                cause: Some(_e.to_string()),
+                message: None,
+                error_type: AppErrorType::DatabaseError,
+            })?;
+
+        if res.matched_count == 0 {
+            return Err(AppError {
+                message: Some("No User Found".to_string()),
+                cause: None,
+                error_type: AppErrorType::NotFoundError,
+            });
         }
+        Ok(())
     }
 
-    pub async fn change_password_email(
+    /// Finds the user an external identity is linked to, linking it first to
+    /// the account with the same email when both the provider and the
+    /// account have verified that address, or creating an account when
+    /// there is none.
+    pub async fn from_identity(
         db: &Database,
+        hash_config: &HashConfig,
+        provider: &str,
+        claims: &IdTokenClaims,
+    ) -> Result<UserDetails, AppError> {
+        let coll = get_coll(db);
+        let identity = Identity {
+            provider: provider.to_string(),
+            subject: claims.sub.clone(),
+        };
+        let linked = coll
+            .find_one(
+                doc! {
+                    "identities": { "$elemMatch": { "provider": provider, "subject": &claims.sub } }
+                },
+                None,
+            )
+            .await
+            .map_err(|_e| AppError {
+                cause: Some(_e.to_string()),
+                message: None,
+                error_type: AppErrorType::DatabaseError,
+            })?;
+        if let Some(user) = linked {
+            return bson::from_document::<UserDetails>(user).map_err(AppError::decode_error);
+        }
+
+        let email = claims.email.as_deref().ok_or(AppError {
+            cause: Some("EMAIL_REQUIRED".to_string()),
+            message: Some("The provider did not share an email address".to_string()),
+            error_type: AppErrorType::BadRequest,
+        })?;
+
+        let user_id = match User::get_user_by_email(db, email).await {
+            Ok(existing) => {
+                // Linking on an unverified address would let anyone who can
+                // register it at the provider take over the account.
+                if !claims.email_verified {
+                    return Err(AppError {
+                        cause: Some("EMAIL_NOT_VERIFIED".to_string()),
+                        message: Some(
+                            "Verify your email with the provider before linking it".to_string(),
+                        ),
+                        error_type: AppErrorType::ForbiddenError,
+                    });
+                }
+                // Nor may an address nobody has proven locally: whoever
+                // registered it chose the password and would keep it.
+                if !existing.email_verified {
+                    return Err(AppError {
+                        cause: Some("ACCOUNT_EMAIL_NOT_VERIFIED".to_string()),
+                        message: Some(
+                            "Sign in with your password and verify your email before linking a provider"
+                                .to_string(),
+                        ),
+                        error_type: AppErrorType::ForbiddenError,
+                    });
+                }
+                let user_id = existing.id.unwrap();
+                coll.update_one(
+                    doc! { "_id": &user_id },
+                    doc! {
+                        "$push": { "identities": bson::to_bson(&identity).unwrap() }
+                    },
+                    None,
+                )
+                .await
+                .map_err(|_e| AppError {
+                    cause: Some(_e.to_string()),
+                    message: None,
+                    error_type: AppErrorType::DatabaseError,
+                })?;
+                user_id
+            }
+            Err(AppError {
+                error_type: AppErrorType::NotFoundError,
+                ..
+            }) => {
+                let mut user = User {
+                    id: None,
+                    username: User::available_username(db, claims, email).await?,
+                    email: email.to_string(),
+                    // Unusable until the owner sets one through password reset.
+                    password: CryptoService::generate_token(32),
+                    user_avatar: claims.picture.clone(),
+                    role: Role::default(),
+                    token_version: 0,
+                    email_verified: claims.email_verified,
+                    pending_email: None,
+                    totp_enabled: false,
+                    totp_secret: None,
+                    recovery_codes: Vec::new(),
+                    status: AccountStatus::default(),
+                    suspended_until: None,
+                    identities: vec![identity],
+                };
+                user.save(db, hash_config).await?;
+                user.id.unwrap()
+            }
+            Err(_e) => return Err(_e),
+        };
+
+        User::get_user_by_id(db, user_id.to_hex().as_str()).await
+    }
+
+    async fn available_username(
+        db: &Database,
+        claims: &IdTokenClaims,
         email: &str,
-        password: &str,
+    ) -> Result<String, AppError> {
+        let base = claims
+            .preferred_username
+            .clone()
+            .or_else(|| claims.name.clone())
+            .unwrap_or_else(|| email.split('@').next().unwrap().to_string());
+        let mut username = base.clone();
+
+        loop {
+            let taken = get_coll(db)
+                .find_one(doc! { "username": &username }, None)
+                .await
+                .map_err(|_e| AppError {
+                    cause: Some(_e.to_string()),
+                    message: None,
+                    error_type: AppErrorType::DatabaseError,
+                })?;
+            if taken.is_none() {
+                return Ok(username);
+            }
+            username = format!("{}-{}", base, CryptoService::generate_token(4));
+        }
+    }
+
+    /// Deletes the account with its sessions, keys and other records. Its
+    /// votes are withdrawn and its content handled according to `mode`. An
+    /// uploaded avatar is removed last; failing that is only logged.
+    pub async fn delete_account(
+        db: &Database,
+        account: &UserDetails,
+        mode: DeletionMode,
     ) -> Result<(), AppError> {
-        let coll = get_coll(&db);
+        let user_id = account.id.clone().unwrap();
+
+        Votes::remove_user(db, &user_id).await?;
+        match mode {
+            DeletionMode::Anonymize => {
+                BlogPost::anonymize_user(db, &user_id).await?;
+                Comments::anonymize_user(db, &user_id).await?;
+            }
+            DeletionMode::Cascade => {
+                BlogPost::delete_by_user(db, &user_id).await?;
+                Comments::delete_by_user(db, &user_id).await?;
+            }
+        }
+
+        for name in ACCOUNT_RECORDS {
+            db.collection(name)
+                .delete_many(doc! { "user_id": &user_id }, None)
+                .await
+                .map_err(AppError::database_error)?;
+        }
+        db.collection("login_attempts")
+            .delete_one(doc! { "_id": format!("account:{}", user_id) }, None)
+            .await
+            .map_err(AppError::database_error)?;
+        get_coll(db)
+            .delete_one(doc! { "_id": &user_id }, None)
+            .await
+            .map_err(AppError::database_error)?;
+
+        if let Some(path) = account.user_avatar.as_deref().and_then(s3_aws::object_path) {
+            if let Err(_e) = s3_aws::delete_file(s3_aws::get_s3_bucket().await, path).await {
+                println!("{:?}", _e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Invalidates every access token, refresh token and API key issued to
+    /// the user.
+    pub async fn revoke_tokens(db: &Database, user_id: &str) -> Result<(), AppError> {
+        let coll = get_coll(db);
+        let user_id = convert_obj_id(user_id).await?;
         match coll
             .update_one(
                 doc! {
-                    "email": email
+                    "_id": &user_id
                 },
                 doc! {
-                    "$set": {
-                        "password": CryptoService::hash_password(password.to_string()).await?,
+                    "$inc": {
+                        "token_version": 1
                     }
                 },
                 None,
@@ -372,16 +1013,56 @@ impl User {
                 message: None,
                 error_type: AppErrorType::DatabaseError,
             }),
-        }
+        }?;
+
+        Session::revoke_all(db, &user_id).await?;
+        ApiKey::revoke_user(db, &user_id).await
     }
 
 }
 
 impl UserCreds {
-    pub async fn validate(&self, db_data: &User) -> Result<bool, AppError> {
-        return Ok(
-            CryptoService::verify_hash(db_data.password.clone(), self.password.clone()).await?,
-        );
+    /// Checks the password and, when it matches a hash made with outdated
+    /// settings such as a legacy bcrypt hash, stores a fresh one.
+    pub async fn validate(
+        &self,
+        db: &Database,
+        hash_config: &HashConfig,
+        db_data: &User,
+    ) -> Result<bool, AppError> {
+        if !CryptoService::verify_hash(db_data.password.clone(), self.password.clone()).await? {
+            return Ok(false);
+        }
+
+        if hash_config.needs_rehash(db_data.password.as_str()) {
+            let rehashed = CryptoService::hash_password(hash_config, self.password.clone()).await?;
+            get_coll(db)
+                .update_one(
+                    doc! {
+                        "_id": db_data.id.as_ref().unwrap(),
+                        "password": &db_data.password
+                    },
+                    doc! {
+                        "$set": { "password": rehashed }
+                    },
+                    None,
+                )
+                .await
+                .map_err(|_e| AppError {
+                    cause: Some(_e.to_string()),
+                    message: None,
+                    error_type: AppErrorType::DatabaseError,
+                })?;
+        }
+        Ok(true)
+    }
+}
+
+fn invalid_code() -> AppError {
+    AppError {
+        cause: Some("INVALID_2FA_CODE".to_string()),
+        message: Some("Invalid two-factor code".to_string()),
+        error_type: AppErrorType::InvalidToken,
     }
 }
 
@@ -391,31 +1072,45 @@ async fn convert_obj_id(id: &str) -> Result<ObjectId, AppError> {
         Err(_e) => Err(AppError {
             cause: Some(_e.to_string()),
             message: None,
-            error_type: AppErrorType::InavlidId,
+            error_type: AppErrorType::InvalidId,
         }),
     }
 }
 
 impl PatchUser {
-    pub async fn patch_user_details(&self, db: &Database, user_id: &str) -> Result<(), AppError> {
-        let coll = get_coll(&db);
+    /// Applies the new username immediately. A changed email is only staged in
+    /// `pending_email`; returns `true` when a confirmation needs to be sent.
+    pub async fn patch_user_details(&self, db: &Database, user_id: &str) -> Result<bool, AppError> {
+        let coll = get_coll(db);
+        let current = User::get_user_by_id(db, user_id).await?;
+        let email_changed = current.email != self.email;
+
+        let update = if email_changed {
+            Email {
+                email: self.email.clone(),
+            }
+            .check_email(db)
+            .await?;
+            doc! { "$set": { "username": &self.username, "pending_email": &self.email } }
+        } else {
+            // Changing the address back drops the unconfirmed change.
+            doc! {
+                "$set": { "username": &self.username },
+                "$unset": { "pending_email": 1 }
+            }
+        };
 
         match coll
             .update_one(
                 doc! {
                     "_id": convert_obj_id(user_id).await?
                 },
-                doc! {
-                    "$set": {
-                        "username": &self.username,
-                        "email": &self.email,
-                    }
-                },
+                update,
                 None,
             )
             .await
         {
-            Ok(_) => Ok(()),
+            Ok(_) => Ok(email_changed),
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),
                 message: None,
@@ -443,14 +1138,14 @@ impl Email {
             .await
         {
             Ok(val) => {
-                if !val.is_none() {
+                if val.is_some() {
                     return Err(AppError {
                         cause: Some("EMAIL_EXISTS".to_string()),
                         message: None,
-                        error_type: AppErrorType::ALREADYEXIST,
+                        error_type: AppErrorType::AlreadyExists,
                     });
                 }
-                return Ok(());
+                Ok(())
             }
             Err(_e) => Err(AppError {
                 cause: Some(_e.to_string()),